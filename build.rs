@@ -0,0 +1,11 @@
+use std::env;
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    // groups the allocator code, see `linker.ld`
+    println!("cargo:rustc-link-arg=-T{}/linker.ld", manifest_dir);
+    println!("cargo:rerun-if-changed=linker.ld");
+    // links the kernel into the top 2 GiB, which the `kernel` code model of
+    // the target requires, the lower half is left to the address spaces
+    println!("cargo:rustc-link-arg=--image-base=0xffffffff80000000");
+}
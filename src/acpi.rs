@@ -0,0 +1,239 @@
+use crate::memory;
+use core::mem;
+use core::ptr;
+use x86_64::PhysAddr;
+
+/// Maximum number of I/O APICs and interrupt source overrides that are recorded
+const MAX_IO_APICS: usize = 4;
+const MAX_OVERRIDES: usize = 16;
+
+/// Root System Description Pointer, found by scanning the BIOS memory
+#[allow(dead_code)] // describes the layout, not all fields are used
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below are only valid for revision 2 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Header shared by all system description tables
+#[allow(dead_code)] // describes the layout, not all fields are used
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// An I/O APIC described by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Maps a legacy ISA IRQ to a different global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The interrupt controller information of the MADT ("APIC" table)
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Set if the system also has 8259 PICs that need to be disabled
+    pub has_legacy_pics: bool,
+    pub processors: usize,
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    /// Returns the global system interrupt for the ISA `irq`, along with
+    /// whether it is active low and level triggered
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+/// Returns a pointer to physical memory through the complete physical memory mapping
+fn phys_ptr<T>(addr: u64) -> *const T {
+    (memory::physical_memory_offset() + addr).as_ptr()
+}
+
+/// Reads a possibly unaligned value from physical memory
+fn read<T>(addr: u64) -> T {
+    unsafe { ptr::read_unaligned(phys_ptr::<T>(addr)) }
+}
+
+fn checksum_valid(addr: u64, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_ptr::<u8>(addr), len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Reads the header of the system description table at `addr`.
+///
+/// Returns `None` if the table is shorter than its header or its checksum
+/// is wrong, so that its length can be trusted afterwards.
+fn read_header(addr: u64) -> Option<SdtHeader> {
+    let header = read::<SdtHeader>(addr);
+    let length = header.length as usize;
+    if length < mem::size_of::<SdtHeader>() || !checksum_valid(addr, length) {
+        return None;
+    }
+    Some(header)
+}
+
+/// Searches `len` bytes starting at `start` for the RSDP
+fn scan_for_rsdp(start: u64, len: u64) -> Option<u64> {
+    (start..start + len).step_by(16).find(|&addr| {
+        let signature = read::<[u8; 8]>(addr);
+        &signature == b"RSD PTR " && checksum_valid(addr, 20)
+    })
+}
+
+/// Finds the RSDP in the extended BIOS data area or the BIOS ROM area
+fn find_rsdp() -> Option<u64> {
+    // the real mode segment of the EBDA is stored at 0x40e
+    let ebda = u64::from(read::<u16>(0x40e)) << 4;
+    let in_ebda = if ebda != 0 {
+        scan_for_rsdp(ebda, 1024)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| scan_for_rsdp(0xe_0000, 0x2_0000))
+}
+
+/// Returns the physical address of the table with the given signature
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp_addr = find_rsdp()?;
+    let rsdp = read::<Rsdp>(rsdp_addr);
+
+    // the extended checksum covers the XSDT address of revision 2 and later
+    let extended_valid = rsdp.revision >= 2
+        && rsdp.length as usize >= mem::size_of::<Rsdp>()
+        && checksum_valid(rsdp_addr, rsdp.length as usize);
+
+    // the XSDT holds 64 bit pointers, the RSDT 32 bit ones
+    let (root, entry_size) = if extended_valid && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+
+    let header = read_header(root)?;
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let first = root + mem::size_of::<SdtHeader>() as u64;
+    (0..entries)
+        .map(|i| {
+            let entry = first + (i * entry_size) as u64;
+            if entry_size == 8 {
+                read::<u64>(entry)
+            } else {
+                u64::from(read::<u32>(entry))
+            }
+        })
+        .find(|&table| &read::<[u8; 4]>(table) == signature && read_header(table).is_some())
+}
+
+/// Finds and parses the MADT.
+///
+/// Requires `memory::init` to have been called, as the tables are read
+/// through the physical memory mapping.
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let header = read::<SdtHeader>(table);
+    // the local APIC address and flags follow the header
+    if (header.length as usize) < mem::size_of::<SdtHeader>() + 8 {
+        return None;
+    }
+
+    let body = table + mem::size_of::<SdtHeader>() as u64;
+    let local_apic_address = read::<u32>(body);
+    let flags = read::<u32>(body + 4);
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(local_apic_address)),
+        has_legacy_pics: flags & 1 != 0,
+        processors: 0,
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+
+    // variable length entries, each starting with its type and length
+    let end = table + u64::from(header.length);
+    let mut entry = body + 8;
+    while entry + 2 <= end {
+        let read_u8 = |offset: u64| read::<u8>(entry + offset);
+        let read_u16 = |offset: u64| read::<u16>(entry + offset);
+        let read_u32 = |offset: u64| read::<u32>(entry + offset);
+        let read_u64 = |offset: u64| read::<u64>(entry + offset);
+
+        let entry_type = read_u8(0);
+        let length = u64::from(read_u8(1));
+        if length < 2 {
+            break;
+        }
+
+        match entry_type {
+            // processor local APIC, bit 0 of the flags marks usable processors
+            0 if read_u32(4) & 1 != 0 => madt.processors += 1,
+            1 => {
+                if let Some(slot) = madt.io_apics.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(IoApicInfo {
+                        id: read_u8(2),
+                        address: PhysAddr::new(u64::from(read_u32(4))),
+                        gsi_base: read_u32(8),
+                    });
+                }
+            }
+            2 => {
+                let flags = read_u16(8);
+                if let Some(slot) = madt.overrides.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(InterruptOverride {
+                        irq: read_u8(3),
+                        gsi: read_u32(4),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+            }
+            // 64 bit local APIC address override
+            5 => madt.local_apic_address = PhysAddr::new(read_u64(4)),
+            _ => {}
+        }
+
+        entry += length;
+    }
+
+    Some(madt)
+}
@@ -1,21 +1,120 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageSize,
+        PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-// use bump::BumpAllocator;
-// use linked_list::LinkedListAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
+use crate::backtrace::Backtrace;
+use crate::memory;
+use crate::{println, serial_println};
+use stats::HeapStats;
+
+// The global allocator is selected through the `alloc-*` cargo features
+#[cfg(feature = "alloc-bump")]
+type HeapAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type HeapAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type HeapAllocator = fixed_size_block::FixedSizeBlockAllocator;
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block"
+)))]
+compile_error!("one of the `alloc-bump`, `alloc-linked-list` or `alloc-fixed-block` features must be enabled");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block")
+))]
+compile_error!("only one of the `alloc-*` features can be enabled, use `--no-default-features` to replace the default");
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::new());
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// In the kernel regions, see `memory::address_space::KERNEL_REGIONS_START`
+pub const HEAP_START: usize = 0x_ffff_c000_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Default limit for how large the heap may grow
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+/// Smallest amount of memory mapped at once when the heap grows
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// The global allocator, keeps statistics around the selected heap allocator,
+/// records live allocations while the leak tracker is running and checks
+/// every allocation if the `heap-debug` feature is enabled
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(not(feature = "heap-debug"))]
+        let ptr = ALLOCATOR.alloc(layout);
+        #[cfg(feature = "heap-debug")]
+        let ptr = debug::alloc(&ALLOCATOR, layout);
+
+        stats::record_alloc(layout.size(), !ptr.is_null());
+        if !ptr.is_null() {
+            tracker::record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(not(feature = "heap-debug"))]
+        ALLOCATOR.dealloc(ptr, layout);
+        #[cfg(feature = "heap-debug")]
+        debug::dealloc(&ALLOCATOR, ptr, layout);
+
+        stats::record_dealloc(layout.size());
+        tracker::record_dealloc(ptr);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // forwarded so allocators can resize in place
+        #[cfg(not(feature = "heap-debug"))]
+        let new_ptr = ALLOCATOR.realloc(ptr, layout, new_size);
+        #[cfg(feature = "heap-debug")]
+        let new_ptr = debug::realloc(&ALLOCATOR, ptr, layout, new_size);
+
+        if !new_ptr.is_null() {
+            stats::record_dealloc(layout.size());
+            tracker::record_dealloc(ptr);
+            tracker::record_alloc(new_ptr, new_size);
+        }
+        stats::record_alloc(new_size, !new_ptr.is_null());
+        new_ptr
+    }
+}
+
+extern "C" {
+    // bounds of the `alloc` crate and allocator code, see `linker.ld`
+    static __allocator_text_start: u8;
+    static __allocator_text_end: u8;
+}
+
+fn is_allocator_code(addr: usize) -> bool {
+    let start = unsafe { core::ptr::addr_of!(__allocator_text_start) } as usize;
+    let end = unsafe { core::ptr::addr_of!(__allocator_text_end) } as usize;
+    (start..end).contains(&addr)
+}
+
+/// Captures the backtrace of the current allocation, starting at the first
+/// caller outside the allocator
+fn allocation_site<const N: usize>() -> Backtrace<N> {
+    Backtrace::capture_skipping(is_allocator_code)
+}
 
 pub struct Dummy;
 
@@ -33,21 +132,10 @@ pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let head_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(head_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+    let page_range = heap_pages(HEAP_START, HEAP_SIZE);
 
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }
+        map_heap_page(page, mapper, frame_allocator)?;
     }
 
     unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) }
@@ -55,11 +143,114 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Returns a snapshot of the heap statistics
+pub fn heap_stats() -> HeapStats {
+    // don't let an interrupt handler allocate while we hold the allocator lock
+    without_interrupts(|| HeapStats::collect(&*ALLOCATOR.lock()))
+}
+
+/// Prints the heap statistics to the screen and the serial port
+pub fn print_meminfo() {
+    let stats = heap_stats();
+    println!("Heap statistics:\n{}", stats);
+    serial_println!("Heap statistics:\n{}", stats);
+}
+
+/// Sets how the linked list allocator picks free regions
+#[cfg(feature = "alloc-linked-list")]
+pub fn set_fit_strategy(strategy: linked_list::FitStrategy) {
+    without_interrupts(|| ALLOCATOR.lock().set_strategy(strategy));
+}
+
+/// Sets the size the heap may grow to, counted from `HEAP_START`.
+///
+/// Memory that is already mapped is not given back when lowering the limit.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Returns the size the heap may grow to
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Maps new pages directly after `heap_end` so that the heap can be
+/// extended by at least `min_size` bytes.
+///
+/// Returns the number of bytes that were mapped, which can be less than
+/// `min_size` if frames ran out midway, or `None` if nothing was mapped.
+/// Requires `memory::init_kernel_memory` to have been called.
+pub(crate) fn grow_heap(heap_end: usize, min_size: usize) -> Option<usize> {
+    // only the kernel heap region can grow, not allocators over other memory
+    if heap_end < HEAP_START {
+        return None;
+    }
+
+    let limit_end = HEAP_START + heap_limit();
+    let size = align_up(min_size.max(HEAP_GROWTH_STEP), Size4KiB::SIZE as usize)
+        .min(limit_end.saturating_sub(heap_end));
+    if size < min_size {
+        return None;
+    }
+
+    // the kernel memory might be locked by the code that is allocating,
+    // in that case we cannot grow instead of deadlocking
+    let mapped = memory::try_with_kernel_memory(|kernel_memory| {
+        let mut mapped = 0;
+        for page in heap_pages(heap_end, size) {
+            let result = map_heap_page(
+                page,
+                &mut kernel_memory.mapper,
+                &mut kernel_memory.frame_allocator,
+            );
+            if result.is_err() {
+                break;
+            }
+            mapped += Size4KiB::SIZE as usize;
+        }
+        mapped
+    })?;
+
+    if mapped == 0 {
+        None
+    } else {
+        Some(mapped)
+    }
+}
+
+fn heap_pages(start: usize, size: usize) -> PageRangeInclusive {
+    let heap_start = VirtAddr::new(start as u64);
+    let heap_end = heap_start + size - 1u64;
+    let heap_start_page = Page::containing_address(heap_start);
+    let heap_end_page = Page::containing_address(heap_end);
+    Page::range_inclusive(heap_start_page, heap_end_page)
+}
+
+fn map_heap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }
+
+    Ok(())
+}
+
 ////////////////////////////////////////////////
 /// Allocators
 pub mod bump;
+#[cfg(feature = "heap-debug")]
+mod debug;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod slab;
+pub mod stats;
+pub mod tracker;
 
 /// Wrapper around spin::Mutex
 pub struct Locked<A> {
@@ -1,6 +1,9 @@
-use super::{align_up, Locked};
+use super::stats::{AllocatorDetails, AllocatorStats};
+use super::{align_up, grow_heap, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr;
+use core::alloc::{AllocError, Allocator};
+use core::cell::Cell;
+use core::ptr::{self, NonNull};
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -27,25 +30,52 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Makes sure that the heap reaches up to `end`, growing it if needed.
+    ///
+    /// Returns false if the heap could not be grown far enough.
+    fn reserve(&mut self, end: usize) -> bool {
+        if end > self.heap_end {
+            // map more memory after the end of the heap
+            match grow_heap(self.heap_end, end - self.heap_end) {
+                Some(grown) => self.heap_end += grown,
+                None => return false,
+            }
+        }
+
+        end <= self.heap_end
+    }
+}
+
+impl AllocatorStats for BumpAllocator {
+    fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    fn details(&self) -> AllocatorDetails {
+        AllocatorDetails::Bump {
+            remaining: self.heap_end - self.next,
+            allocations: self.allocations,
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock();
 
-        // TODO alignment and bound check
         let alloc_start = align_up(bump.next, layout.align());
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
             None => return ptr::null_mut(),
         };
 
-        if alloc_end > bump.heap_end {
-            ptr::null_mut()
-        } else {
+        if bump.reserve(alloc_end) {
             bump.next = alloc_end;
             bump.allocations += 1;
             alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
         }
     }
 
@@ -57,4 +87,190 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             bump.next = bump.heap_start;
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        {
+            let mut bump = self.lock();
+
+            // the most recent allocation ends at `next`, so it can be resized in place
+            if ptr as usize + layout.size() == bump.next {
+                return match (ptr as usize).checked_add(new_size) {
+                    Some(new_end) if bump.reserve(new_end) => {
+                        bump.next = new_end;
+                        ptr
+                    }
+                    _ => ptr::null_mut(),
+                };
+            }
+        }
+
+        // any other allocation needs to be moved
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// A bump allocator over any mapped memory region.
+///
+/// Allocations are never freed individually. Instead the whole arena is
+/// cleared with `reset`, or everything allocated after a `Checkpoint` is
+/// released with `rollback`. Only the most recent allocation can be freed,
+/// grown or shrunk in place.
+///
+/// Implements `Allocator`, so collections can be placed in it with
+/// e.g. `Vec::new_in(&arena)`.
+pub struct Arena {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,
+    /// Start of the most recent allocation
+    last: Cell<usize>,
+}
+
+/// A position in an `Arena` that it can be rolled back to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint(usize);
+
+impl Arena {
+    /// Creates an arena over the given memory region.
+    ///
+    /// Unsafe because the region must be mapped, writable and not used by
+    /// anything else for the lifetime of the arena.
+    pub unsafe fn new(start: usize, size: usize) -> Self {
+        Arena {
+            start,
+            end: start + size,
+            next: Cell::new(start),
+            last: Cell::new(start),
+        }
+    }
+
+    /// Number of bytes handed out, including alignment padding
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    /// Number of bytes that can still be allocated
+    pub fn remaining(&self) -> usize {
+        self.end - self.next.get()
+    }
+
+    /// Frees all allocations at once.
+    ///
+    /// Takes `&mut self` so that no collection can still be using the arena.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+        self.last.set(self.start);
+    }
+
+    /// Remembers the current position so that it can be rolled back to.
+    ///
+    /// Checkpoints can be nested, rolling back to an older checkpoint
+    /// also releases everything after any newer one.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.next.get())
+    }
+
+    /// Frees everything that was allocated after the given checkpoint.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        assert!(
+            checkpoint.0 >= self.start && checkpoint.0 <= self.next.get(),
+            "checkpoint is not part of the allocated arena"
+        );
+        self.next.set(checkpoint.0);
+        self.last.set(checkpoint.0);
+    }
+
+    /// Bumps `next` to the end of a new allocation.
+    fn bump(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let alloc_start = align_up(self.next.get(), layout.align());
+        let alloc_end = alloc_start.checked_add(layout.size()).ok_or(AllocError)?;
+        if alloc_end > self.end {
+            return Err(AllocError);
+        }
+
+        self.next.set(alloc_end);
+        self.last.set(alloc_start);
+        Ok(slice_ptr(alloc_start, layout.size()))
+    }
+
+    /// Returns whether `ptr` is the most recent allocation
+    fn is_last(&self, ptr: NonNull<u8>, layout: Layout) -> bool {
+        ptr.as_ptr() as usize == self.last.get()
+            && self.last.get() + layout.size() == self.next.get()
+    }
+
+    /// Resizes the most recent allocation, returns an error if it doesn't fit.
+    fn resize_last(&self, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let start = self.last.get();
+        let new_end = start.checked_add(new_layout.size()).ok_or(AllocError)?;
+        if new_end > self.end || start % new_layout.align() != 0 {
+            return Err(AllocError);
+        }
+
+        self.next.set(new_end);
+        Ok(slice_ptr(start, new_layout.size()))
+    }
+}
+
+fn slice_ptr(addr: usize, len: usize) -> NonNull<[u8]> {
+    let slice = ptr::slice_from_raw_parts_mut(addr as *mut u8, len);
+    NonNull::new(slice).expect("arena allocation at null address")
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.bump(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // only the most recent allocation can be given back
+        if self.is_last(ptr, layout) {
+            self.next.set(self.last.get());
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_last(ptr, old_layout) {
+            if let Ok(resized) = self.resize_last(new_layout) {
+                return Ok(resized);
+            }
+        }
+
+        let new_ptr = self.bump(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_layout.size());
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_last(ptr, old_layout) {
+            if let Ok(resized) = self.resize_last(new_layout) {
+                return Ok(resized);
+            }
+        }
+
+        // shrinking never needs more memory, the old allocation is still large enough
+        if ptr.as_ptr() as usize % new_layout.align() == 0 {
+            return Ok(slice_ptr(ptr.as_ptr() as usize, new_layout.size()));
+        }
+
+        let new_ptr = self.bump(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, new_layout.size());
+        Ok(new_ptr)
+    }
 }
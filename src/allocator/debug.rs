@@ -0,0 +1,150 @@
+use super::align_up;
+use crate::backtrace::Backtrace;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, slice};
+
+/// Number of guard bytes on each side of an allocation
+const GUARD_SIZE: usize = 16;
+const GUARD_BYTE: u8 = 0xfd;
+/// Written over memory once it is freed
+const POISON_BYTE: u8 = 0xdd;
+
+const MAGIC_LIVE: u64 = 0xa110_c8ed;
+const MAGIC_FREED: u64 = 0xdead_f4ee;
+
+/// Number of return addresses remembered for every allocation
+const SITE_DEPTH: usize = 4;
+
+/// Stored in front of every allocation when heap debugging is enabled.
+///
+/// The memory handed to the underlying allocator is laid out as
+/// `| padding | Header | front guard | user data | rear guard |`.
+#[repr(C)]
+struct Header {
+    /// Left alone because allocators store their free list nodes here
+    /// once the allocation is freed, this keeps `magic` readable for
+    /// double free detection.
+    _reserved: [usize; 2],
+    magic: u64,
+    size: usize,
+    align: usize,
+    /// Start of the allocation made by the underlying allocator
+    base: usize,
+    site: Backtrace<SITE_DEPTH>,
+}
+
+/// Offset of the user data from the start of the underlying allocation
+fn data_offset(align: usize) -> usize {
+    align_up(mem::size_of::<Header>() + GUARD_SIZE, align)
+}
+
+/// Layout requested from the underlying allocator for the given user layout
+fn inner_layout(layout: Layout) -> Option<Layout> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let size = data_offset(align)
+        .checked_add(layout.size())?
+        .checked_add(GUARD_SIZE)?;
+    Layout::from_size_align(size, align).ok()
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(GUARD_SIZE + mem::size_of::<Header>()) as *mut Header
+}
+
+unsafe fn guard_intact(start: *const u8) -> bool {
+    slice::from_raw_parts(start, GUARD_SIZE)
+        .iter()
+        .all(|&b| b == GUARD_BYTE)
+}
+
+pub(super) unsafe fn alloc(inner: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+    let inner_layout = match inner_layout(layout) {
+        Some(inner_layout) => inner_layout,
+        None => return ptr::null_mut(),
+    };
+
+    let base = inner.alloc(inner_layout);
+    if base.is_null() {
+        return base;
+    }
+
+    let ptr = base.add(data_offset(inner_layout.align()));
+    header(ptr).write(Header {
+        _reserved: [0; 2],
+        magic: MAGIC_LIVE,
+        size: layout.size(),
+        align: layout.align(),
+        base: base as usize,
+        site: super::allocation_site(),
+    });
+    ptr::write_bytes(ptr.sub(GUARD_SIZE), GUARD_BYTE, GUARD_SIZE);
+    ptr::write_bytes(ptr.add(layout.size()), GUARD_BYTE, GUARD_SIZE);
+
+    ptr
+}
+
+pub(super) unsafe fn dealloc(inner: &impl GlobalAlloc, ptr: *mut u8, layout: Layout) {
+    let header = &mut *header(ptr);
+
+    match header.magic {
+        MAGIC_LIVE => {}
+        MAGIC_FREED => panic!(
+            "heap corruption: double free of {:p} ({} bytes, allocated at {:?})",
+            ptr, header.size, header.site
+        ),
+        _ => panic!(
+            "heap corruption: {:p} was not allocated by the heap or its header was overwritten",
+            ptr
+        ),
+    }
+
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "heap corruption: {:p} allocated with size {} align {} but freed with size {} align {} (allocated at {:?})",
+            ptr,
+            header.size,
+            header.align,
+            layout.size(),
+            layout.align(),
+            header.site
+        );
+    }
+
+    if !guard_intact(ptr.sub(GUARD_SIZE)) {
+        panic!(
+            "heap corruption: buffer underflow before {:p} ({} bytes, allocated at {:?})",
+            ptr, header.size, header.site
+        );
+    }
+    if !guard_intact(ptr.add(layout.size())) {
+        panic!(
+            "heap corruption: buffer overflow after {:p} ({} bytes, allocated at {:?})",
+            ptr, header.size, header.site
+        );
+    }
+
+    let base = header.base as *mut u8;
+    let inner_layout = inner_layout(layout).expect("layout was valid on allocation");
+
+    // poison the user data so use after free shows up as 0xdd bytes
+    ptr::write_bytes(ptr, POISON_BYTE, layout.size());
+    header.magic = MAGIC_FREED;
+
+    inner.dealloc(base, inner_layout);
+}
+
+pub(super) unsafe fn realloc(
+    inner: &impl GlobalAlloc,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    // always move so that both the old and the new allocation get checked
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = alloc(inner, new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        dealloc(inner, ptr, layout);
+    }
+    new_ptr
+}
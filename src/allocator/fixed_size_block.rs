@@ -1,4 +1,5 @@
-use super::Locked;
+use super::stats::{AllocatorDetails, AllocatorStats};
+use super::{grow_heap, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
 
@@ -10,7 +11,7 @@ struct ListNode {
 ///
 /// The sizes must each be a power of 2 because they are used
 /// as the block alignment (alignments must be powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
@@ -43,10 +44,49 @@ impl FixedSizeBlockAllocator {
     }
 
     /// Allocates using the fallback allocator.
+    ///
+    /// Grows the heap if the fallback allocator has no region left that
+    /// fits the layout.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // the padding for the alignment might be needed on top of the size
+        let required = layout.size() + layout.align();
+        match grow_heap(self.fallback_allocator.top(), required) {
+            Some(grown) => {
+                // the new pages are mapped directly after the current top
+                unsafe { self.fallback_allocator.extend(grown) };
+                match self.fallback_allocator.allocate_first_fit(layout) {
+                    Ok(ptr) => ptr.as_ptr(),
+                    Err(_) => ptr::null_mut(),
+                }
+            }
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+impl AllocatorStats for FixedSizeBlockAllocator {
+    fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    fn details(&self) -> AllocatorDetails {
+        let mut free_blocks = [0; BLOCK_SIZES.len()];
+        for (count, head) in free_blocks.iter_mut().zip(self.list_heads.iter()) {
+            let mut current = head.as_deref();
+            while let Some(node) = current {
+                *count += 1;
+                current = node.next.as_deref();
+            }
+        }
+
+        AllocatorDetails::FixedSizeBlock {
+            free_blocks,
+            fallback_free: self.fallback_allocator.free(),
+            fallback_used: self.fallback_allocator.used(),
         }
     }
 }
@@ -1,4 +1,5 @@
-use super::{align_up, Locked};
+use super::stats::{AllocatorDetails, AllocatorStats};
+use super::{align_up, grow_heap, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
@@ -21,8 +22,28 @@ impl ListNode {
     }
 }
 
+/// How a free region is picked for an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// Use the first region that is large enough
+    FirstFit,
+    /// Use the smallest region that is large enough
+    BestFit,
+    /// Like first fit, but start searching where the last allocation ended
+    NextFit,
+}
+
+/// A heap allocator that keeps the free regions in a linked list.
+///
+/// The list is sorted by address so that adjacent free regions can be
+/// merged when memory is freed.
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_start: usize,
+    heap_end: usize,
+    strategy: FitStrategy,
+    /// End address of the last allocation, used by `FitStrategy::NextFit`
+    next_fit_cursor: usize,
 }
 
 impl LinkedListAllocator {
@@ -30,27 +51,88 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_start: 0,
+            heap_end: 0,
+            strategy: FitStrategy::FirstFit,
+            next_fit_cursor: 0,
         }
     }
 
+    /// Changes how free regions are picked for new allocations
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Returns the strategy used to pick free regions
+    pub fn strategy(&self) -> FitStrategy {
+        self.strategy
+    }
+
     /// Initialize allocator with given heap bounds
     /// unsafe becuase a valid range needs to be provided
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size)
     }
 
-    /// Adds given memory region to the front of the list
+    /// Maps more memory after the end of the heap and adds it as a free region.
+    ///
+    /// Returns false if the heap could not grow.
+    fn grow(&mut self, min_size: usize) -> bool {
+        match grow_heap(self.heap_end, min_size) {
+            Some(grown) => {
+                unsafe { self.add_free_region(self.heap_end, grown) };
+                self.heap_end += grown;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds given memory region to the list, merging it with adjacent free regions
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        // find the last region that starts before the new one, the list is sorted by address
+        let mut current = &mut self.head;
+        let mut is_head = true;
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+            is_head = false;
+        }
+
+        // freeing memory that is already free would corrupt the list
+        assert!(is_head || current.end_addr() <= addr, "freed region overlaps free memory");
+        if let Some(next) = current.next.as_ref() {
+            assert!(addr + size <= next.start_addr(), "freed region overlaps free memory");
+        }
+
+        if !is_head && current.end_addr() == addr {
+            // directly follows the previous region -> grow that one
+            current.size += size;
+        } else {
+            // create a new list node and insert it after the previous region
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+            current = current.next.as_mut().unwrap();
+        }
+
+        // the next region might now directly follow as well
+        let merge_next = current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() == current.end_addr());
+        if merge_next {
+            let next = current.next.take().unwrap();
+            current.size += next.size;
+            current.next = next.next.take();
+        }
     }
 
     /// Looks for a free region with the given size and alignment and removes
@@ -58,31 +140,74 @@ impl LinkedListAllocator {
     ///
     /// Returns a tuple of the list node and the start address of the allocation.
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
-        // reference to current list node, updated for each iteration
-        let mut current = &mut self.head;
+        let region_addr = self.select_region(size, align)?;
+        let region = self.remove_region(region_addr);
+        let alloc_start = Self::alloc_from_region(region, size, align)
+            .expect("selected region is not suitable for the allocation");
 
-        // look for a large enough memory region in linked list
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                // region suitable for allocation -> remove node from list
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                // region not suitable -> continue with next region
-                current = current.next.as_mut().unwrap()
+        Some((region, alloc_start))
+    }
+
+    /// Picks a suitable free region according to the fit strategy.
+    ///
+    /// Returns the start address of the region.
+    fn select_region(&self, size: usize, align: usize) -> Option<usize> {
+        let mut candidate: Option<&ListNode> = None;
+        let mut current = self.head.next.as_deref();
+
+        while let Some(region) = current {
+            if Self::alloc_from_region(region, size, align).is_ok() {
+                match self.strategy {
+                    FitStrategy::FirstFit => return Some(region.start_addr()),
+                    FitStrategy::NextFit if region.start_addr() >= self.next_fit_cursor => {
+                        return Some(region.start_addr())
+                    }
+                    FitStrategy::NextFit => {
+                        // wrap around to the first suitable region if none follows the cursor
+                        if candidate.is_none() {
+                            candidate = Some(region);
+                        }
+                    }
+                    FitStrategy::BestFit => {
+                        if candidate.map_or(true, |best| region.size < best.size) {
+                            candidate = Some(region);
+                        }
+                    }
+                }
             }
+            current = region.next.as_deref();
         }
 
-        None
+        candidate.map(ListNode::start_addr)
+    }
+
+    /// Unlinks the region starting at `addr` from the list of free regions
+    fn remove_region(&mut self, addr: usize) -> &'static mut ListNode {
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() != addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let region = current.next.take().expect("region is not in the free list");
+        current.next = region.next.take();
+        region
     }
 
     /// try to use the given region for an allocation with given size and alignment.
     ///
     /// Returns the allocation start address on success.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            // the padding in front of the allocation becomes a free region
+            // again, so it needs to be able to hold a ListNode as well
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -116,6 +241,28 @@ impl LinkedListAllocator {
     }
 }
 
+impl AllocatorStats for LinkedListAllocator {
+    fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    fn details(&self) -> AllocatorDetails {
+        let mut free_regions = 0;
+        let mut free_bytes = 0;
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            free_regions += 1;
+            free_bytes += region.size;
+            current = region.next.as_deref();
+        }
+
+        AllocatorDetails::LinkedList {
+            free_regions,
+            free_bytes,
+        }
+    }
+}
+
 // Implementation for usage of allocator
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -123,14 +270,26 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let mut found = allocator.find_region(size, align);
+        if found.is_none() && allocator.grow(size + align) {
+            found = allocator.find_region(size, align);
+        }
+
+        if let Some((region, alloc_start)) = found {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
 
+            if alloc_start > region_start {
+                allocator.add_free_region(region_start, alloc_start - region_start);
+            }
+
+            let excess_size = region_end - alloc_end;
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
 
+            allocator.next_fit_cursor = alloc_end;
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -0,0 +1,429 @@
+use super::align_up;
+use crate::memory;
+use crate::{println, serial_println};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Every slab is a single page taken directly from the frame allocator
+const SLAB_SIZE: usize = 4096;
+
+/// Maximum number of caches that show up in the slab report
+const MAX_CACHES: usize = 32;
+
+static CACHES: Mutex<[Option<&'static ObjectCache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Header at the start of every slab page
+struct Slab {
+    cache: *const ObjectCache,
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Written into every free object of a slab
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// A doubly linked list of slabs
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            None
+        } else {
+            self.remove(slab);
+            Some(slab)
+        }
+    }
+}
+
+struct CacheInner {
+    /// Slabs with free and used objects, allocations are served from here first
+    partial: SlabList,
+    /// Slabs without free objects
+    full: SlabList,
+    /// Slabs without used objects, at most one is kept around
+    empty: SlabList,
+    objects_in_use: usize,
+}
+
+// the slabs are only accessed while holding the cache lock
+unsafe impl Send for CacheInner {}
+
+/// A cache of equally sized objects.
+///
+/// Objects are carved out of whole pages ("slabs") that are taken from the
+/// kernel frame allocator, so allocating from a cache neither uses nor
+/// fragments the heap. Slabs that become empty are given back to the frame
+/// allocator, keeping at most one around for the next allocation.
+pub struct ObjectCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    registered: AtomicBool,
+    inner: Mutex<CacheInner>,
+}
+
+/// Usage of a single object cache
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub empty_slabs: usize,
+    pub objects_in_use: usize,
+}
+
+impl ObjectCache {
+    /// Creates a cache for objects with the given size and alignment.
+    ///
+    /// No memory is used until the first allocation.
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        // free objects need to be able to hold a `FreeObject`
+        let align = if align > mem::align_of::<FreeObject>() {
+            align
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        let size = if size > mem::size_of::<FreeObject>() {
+            size
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+
+        ObjectCache {
+            name,
+            object_size: (size + align - 1) / align * align,
+            align,
+            registered: AtomicBool::new(false),
+            inner: Mutex::new(CacheInner {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                objects_in_use: 0,
+            }),
+        }
+    }
+
+    /// Creates a cache for values of type `T`
+    pub const fn of<T>(name: &'static str) -> Self {
+        Self::new(name, mem::size_of::<T>(), mem::align_of::<T>())
+    }
+
+    /// Offset of the first object from the start of a slab
+    fn first_object_offset(&self) -> usize {
+        align_up(mem::size_of::<Slab>(), self.align)
+    }
+
+    /// Number of objects that fit into a single slab
+    pub fn objects_per_slab(&self) -> usize {
+        SLAB_SIZE.saturating_sub(self.first_object_offset()) / self.object_size
+    }
+
+    /// Allocates an object from the cache.
+    ///
+    /// Returns `None` if no new slab could be allocated.
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        assert!(
+            self.objects_per_slab() > 0,
+            "objects of cache {} are too large for a slab",
+            self.name
+        );
+        self.register();
+
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            unsafe {
+                let slab = match inner.partial.head {
+                    slab if !slab.is_null() => slab,
+                    _ => {
+                        let slab = match inner.empty.pop() {
+                            Some(slab) => slab,
+                            None => self.new_slab()?,
+                        };
+                        inner.partial.push(slab);
+                        slab
+                    }
+                };
+
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).in_use += 1;
+                inner.objects_in_use += 1;
+
+                if (*slab).free.is_null() {
+                    inner.partial.remove(slab);
+                    inner.full.push(slab);
+                }
+
+                NonNull::new(object as *mut u8)
+            }
+        })
+    }
+
+    /// Returns an object to the cache.
+    ///
+    /// Unsafe because the object must have been allocated from this cache
+    /// and must not be used afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let addr = ptr.as_ptr() as usize;
+        let slab = (addr & !(SLAB_SIZE - 1)) as *mut Slab;
+        assert!(
+            (*slab).cache == self as *const ObjectCache,
+            "object {:p} does not belong to cache {}",
+            ptr,
+            self.name
+        );
+        assert_eq!(
+            (addr - slab as usize - self.first_object_offset()) % self.object_size,
+            0,
+            "pointer {:p} is not the start of an object",
+            ptr
+        );
+
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+
+            let was_full = (*slab).free.is_null();
+            let object = addr as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            inner.objects_in_use -= 1;
+
+            if was_full {
+                inner.full.remove(slab);
+                inner.partial.push(slab);
+            }
+
+            if (*slab).in_use == 0 {
+                inner.partial.remove(slab);
+                // keep one empty slab around so that alternating alloc/free
+                // does not go to the frame allocator every time
+                if inner.empty.len > 0 && release_slab(slab) {
+                    return;
+                }
+                inner.empty.push(slab);
+            }
+        })
+    }
+
+    /// Gives all empty slabs back to the frame allocator.
+    ///
+    /// Returns the number of slabs released.
+    pub fn shrink(&self) -> usize {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let mut released = 0;
+            while let Some(slab) = unsafe { inner.empty.pop() } {
+                if unsafe { release_slab(slab) } {
+                    released += 1;
+                } else {
+                    unsafe { inner.empty.push(slab) };
+                    break;
+                }
+            }
+            released
+        })
+    }
+
+    /// Returns the current usage of the cache
+    pub fn stats(&self) -> CacheStats {
+        without_interrupts(|| {
+            let inner = self.inner.lock();
+            CacheStats {
+                name: self.name,
+                object_size: self.object_size,
+                objects_per_slab: self.objects_per_slab(),
+                slabs: inner.partial.len + inner.full.len + inner.empty.len,
+                empty_slabs: inner.empty.len,
+                objects_in_use: inner.objects_in_use,
+            }
+        })
+    }
+
+    /// Takes a page from the frame allocator and fills its free list
+    unsafe fn new_slab(&self) -> Option<*mut Slab> {
+        let frame: PhysFrame = memory::try_with_kernel_memory(|kernel_memory| {
+            kernel_memory.frame_allocator.allocate_frame()
+        })??;
+
+        // the complete physical memory is mapped, so the page is accessible right away
+        let virt = memory::physical_memory_offset() + frame.start_address().as_u64();
+        let slab = virt.as_mut_ptr::<Slab>();
+
+        let mut free = ptr::null_mut();
+        let first = virt.as_u64() as usize + self.first_object_offset();
+        for i in (0..self.objects_per_slab()).rev() {
+            let object = (first + i * self.object_size) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+        }
+
+        slab.write(Slab {
+            cache: self,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        Some(slab)
+    }
+
+    /// Adds the cache to the list shown by `print_slabinfo`
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        without_interrupts(|| {
+            let mut caches = CACHES.lock();
+            if let Some(slot) = caches.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(self);
+            }
+        });
+    }
+}
+
+/// Gives the page of an empty slab back to the frame allocator.
+///
+/// Returns false if the frame allocator is currently not available.
+unsafe fn release_slab(slab: *mut Slab) -> bool {
+    let virt = VirtAddr::from_ptr(slab);
+    let phys = PhysAddr::new(virt.as_u64() - memory::physical_memory_offset().as_u64());
+    let frame: PhysFrame = PhysFrame::containing_address(phys);
+
+    memory::try_with_kernel_memory(|kernel_memory| {
+        kernel_memory.frame_allocator.deallocate_frame(frame)
+    })
+    .is_some()
+}
+
+/// An object cache for values of type `T`
+pub struct TypedCache<T> {
+    cache: ObjectCache,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        TypedCache {
+            cache: ObjectCache::of::<T>(name),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves `value` into an object from the cache.
+    ///
+    /// Gives the value back if no memory is available.
+    pub fn alloc(&'static self, value: T) -> Result<NonNull<T>, T> {
+        match self.cache.alloc() {
+            Some(ptr) => {
+                let ptr = ptr.cast::<T>();
+                unsafe { ptr.as_ptr().write(value) };
+                Ok(ptr)
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Drops the value and returns its memory to the cache.
+    ///
+    /// Unsafe because the value must have been allocated from this cache
+    /// and must not be used afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        ptr::drop_in_place(ptr.as_ptr());
+        self.cache.free(ptr.cast());
+    }
+
+    /// See `ObjectCache::shrink`
+    pub fn shrink(&self) -> usize {
+        self.cache.shrink()
+    }
+
+    /// See `ObjectCache::stats`
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+/// Gives the empty slabs of every cache back to the frame allocator.
+///
+/// Returns the number of slabs released.
+pub fn shrink_all() -> usize {
+    let caches = without_interrupts(|| *CACHES.lock());
+    caches.iter().flatten().map(|cache| cache.shrink()).sum()
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:>6} {:>8} {:>6} {:>6} {:>8}",
+            self.name,
+            self.object_size,
+            self.objects_per_slab,
+            self.slabs,
+            self.empty_slabs,
+            self.objects_in_use
+        )
+    }
+}
+
+/// Prints the usage of every cache to the screen and the serial port
+pub fn print_slabinfo() {
+    let caches = without_interrupts(|| *CACHES.lock());
+    let header = "name               size per_slab  slabs  empty   in_use";
+
+    println!("{}", header);
+    serial_println!("{}", header);
+    for cache in caches.iter().flatten() {
+        let stats = cache.stats();
+        println!("{}", stats);
+        serial_println!("{}", stats);
+    }
+}
@@ -0,0 +1,129 @@
+use super::fixed_size_block::BLOCK_SIZES;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILED_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Implemented by every heap allocator so its state can be reported
+pub trait AllocatorStats {
+    /// Number of bytes currently mapped for the heap
+    fn heap_size(&self) -> usize;
+
+    /// Allocator specific view of the free memory
+    fn details(&self) -> AllocatorDetails;
+}
+
+/// Free memory as seen by the allocator in use
+#[derive(Debug, Clone, Copy)]
+pub enum AllocatorDetails {
+    Bump {
+        /// Bytes between the next allocation and the end of the heap
+        remaining: usize,
+        /// Allocations that have not been freed yet
+        allocations: usize,
+    },
+    LinkedList {
+        free_regions: usize,
+        free_bytes: usize,
+    },
+    FixedSizeBlock {
+        /// Length of the free list for each entry of `BLOCK_SIZES`
+        free_blocks: [usize; BLOCK_SIZES.len()],
+        fallback_free: usize,
+        fallback_used: usize,
+    },
+}
+
+/// A snapshot of the heap statistics.
+///
+/// Byte counts are the sizes requested by the callers, not including
+/// padding or block rounding done by the allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub failed_allocations: u64,
+    pub details: AllocatorDetails,
+}
+
+impl HeapStats {
+    pub(super) fn collect(allocator: &impl AllocatorStats) -> Self {
+        HeapStats {
+            heap_size: allocator.heap_size(),
+            bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+            peak_bytes_in_use: PEAK_BYTES_IN_USE.load(Ordering::Relaxed),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+            failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+            details: allocator.details(),
+        }
+    }
+}
+
+/// Called by the global allocator after every allocation attempt
+pub(super) fn record_alloc(size: usize, success: bool) {
+    if !success {
+        FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let in_use = BYTES_IN_USE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES_IN_USE.fetch_max(in_use, Ordering::Relaxed);
+}
+
+/// Called by the global allocator on every deallocation
+pub(super) fn record_dealloc(size: usize) {
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_sub(size, Ordering::Relaxed);
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Heap size:          {:>10} bytes", self.heap_size)?;
+        writeln!(f, "In use:             {:>10} bytes", self.bytes_in_use)?;
+        writeln!(f, "Peak in use:        {:>10} bytes", self.peak_bytes_in_use)?;
+        writeln!(f, "Allocations:        {:>10}", self.allocations)?;
+        writeln!(f, "Deallocations:      {:>10}", self.deallocations)?;
+        write!(f, "Failed allocations: {:>10}", self.failed_allocations)?;
+
+        match self.details {
+            AllocatorDetails::Bump {
+                remaining,
+                allocations,
+            } => {
+                writeln!(f)?;
+                writeln!(f, "Bump remaining:     {:>10} bytes", remaining)?;
+                write!(f, "Live allocations:   {:>10}", allocations)
+            }
+            AllocatorDetails::LinkedList {
+                free_regions,
+                free_bytes,
+            } => {
+                writeln!(f)?;
+                writeln!(f, "Free regions:       {:>10}", free_regions)?;
+                write!(f, "Free bytes:         {:>10} bytes", free_bytes)
+            }
+            AllocatorDetails::FixedSizeBlock {
+                free_blocks,
+                fallback_free,
+                fallback_used,
+            } => {
+                for (size, count) in BLOCK_SIZES.iter().zip(free_blocks.iter()) {
+                    writeln!(f)?;
+                    write!(f, "Free {:>4} byte blocks: {:>7}", size, count)?;
+                }
+                writeln!(f)?;
+                writeln!(f, "Fallback free:      {:>10} bytes", fallback_free)?;
+                write!(f, "Fallback used:      {:>10} bytes", fallback_used)
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::backtrace::Backtrace;
+use crate::serial_println;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of slots in the table, one of them always stays empty
+const CAPACITY: usize = 1024;
+
+/// Number of return addresses remembered for every allocation
+const SITE_DEPTH: usize = 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Allocations that were not recorded because the table was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+static TABLE: Mutex<Table> = Mutex::new(Table::new());
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Address of the allocation, zero marks an empty slot
+    ptr: usize,
+    size: usize,
+    /// Time stamp counter value when the allocation was made
+    timestamp: u64,
+    site: Backtrace<SITE_DEPTH>,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        ptr: 0,
+        size: 0,
+        timestamp: 0,
+        site: Backtrace::empty(),
+    };
+}
+
+/// Hash table of live allocations keyed by address.
+///
+/// Uses linear probing so that no memory has to be allocated, which would
+/// recurse into the allocator. At least one slot is always empty, which ends
+/// every probe for an address that is not in the table.
+struct Table {
+    entries: [Entry; CAPACITY],
+    len: usize,
+    bytes: usize,
+}
+
+impl Table {
+    const fn new() -> Self {
+        Table {
+            entries: [Entry::EMPTY; CAPACITY],
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    fn slot(ptr: usize) -> usize {
+        // allocations are at least 8 byte aligned, so the low bits carry no information
+        (ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) % CAPACITY
+    }
+
+    fn insert(&mut self, entry: Entry) -> bool {
+        if self.len == CAPACITY - 1 {
+            return false;
+        }
+
+        let mut index = Self::slot(entry.ptr);
+        while self.entries[index].ptr != 0 {
+            index = (index + 1) % CAPACITY;
+        }
+        self.entries[index] = entry;
+        self.len += 1;
+        self.bytes += entry.size;
+        true
+    }
+
+    fn get(&self, ptr: usize) -> Option<&Entry> {
+        let mut index = Self::slot(ptr);
+        loop {
+            match self.entries[index].ptr {
+                0 => return None,
+                p if p == ptr => return Some(&self.entries[index]),
+                _ => index = (index + 1) % CAPACITY,
+            }
+        }
+    }
+
+    fn remove(&mut self, ptr: usize) {
+        let mut index = Self::slot(ptr);
+        loop {
+            match self.entries[index].ptr {
+                // not tracked, e.g. allocated before tracking started
+                0 => return,
+                p if p == ptr => break,
+                _ => index = (index + 1) % CAPACITY,
+            }
+        }
+
+        self.len -= 1;
+        self.bytes -= self.entries[index].size;
+        self.entries[index] = Entry::EMPTY;
+
+        // move following entries back so that lookups don't stop at the hole
+        let mut hole = index;
+        let mut next = (index + 1) % CAPACITY;
+        while self.entries[next].ptr != 0 {
+            let home = Self::slot(self.entries[next].ptr);
+            // the entry may fill the hole if its home slot is not between hole and next
+            let distance_to_home = (next + CAPACITY - home) % CAPACITY;
+            let distance_to_hole = (next + CAPACITY - hole) % CAPACITY;
+            if distance_to_home >= distance_to_hole {
+                self.entries[hole] = self.entries[next];
+                self.entries[next] = Entry::EMPTY;
+                hole = next;
+            }
+            next = (next + 1) % CAPACITY;
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Table::new();
+    }
+}
+
+/// Starts recording allocations.
+///
+/// Only allocations made while tracking is enabled are reported by `dump`.
+pub fn start() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stops recording allocations, already recorded ones are kept.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Forgets all recorded allocations
+pub fn clear() {
+    without_interrupts(|| TABLE.lock().clear());
+    DROPPED.store(0, Ordering::Relaxed);
+}
+
+/// Returns the number of tracked allocations that are still live and their total size
+pub fn live_allocations() -> (usize, usize) {
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        (table.len, table.bytes)
+    })
+}
+
+/// Returns the number of allocations that were not tracked because the table was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Returns the return address into the code that made the tracked allocation at `ptr`
+pub fn allocation_site(ptr: *const u8) -> Option<usize> {
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        table.get(ptr as usize)?.site.iter().next()
+    })
+}
+
+/// Called by the global allocator after every successful allocation
+pub(super) fn record_alloc(ptr: *mut u8, size: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let entry = Entry {
+        ptr: ptr as usize,
+        size,
+        timestamp: unsafe { _rdtsc() },
+        site: super::allocation_site(),
+    };
+
+    let inserted = without_interrupts(|| TABLE.lock().insert(entry));
+    if !inserted {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Called by the global allocator on every deallocation
+pub(super) fn record_dealloc(ptr: *mut u8) {
+    // still remove entries after tracking stopped, they would be reported as leaks otherwise
+    without_interrupts(|| {
+        let mut table = TABLE.lock();
+        if table.len > 0 {
+            table.remove(ptr as usize);
+        }
+    });
+}
+
+/// Prints every tracked allocation that is still live over serial.
+///
+/// The age is given in time stamp counter cycles.
+pub fn dump() {
+    let now = unsafe { _rdtsc() };
+
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        serial_println!(
+            "{} live allocations, {} bytes ({} not tracked)",
+            table.len,
+            table.bytes,
+            DROPPED.load(Ordering::Relaxed)
+        );
+
+        for entry in table.entries.iter().filter(|e| e.ptr != 0) {
+            serial_println!(
+                "  {:#x} {:>8} bytes  age {:>14} cycles  at {:?}",
+                entry.ptr,
+                entry.size,
+                now.wrapping_sub(entry.timestamp),
+                entry.site
+            );
+        }
+    });
+}
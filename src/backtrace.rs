@@ -0,0 +1,89 @@
+use core::arch::asm;
+use core::fmt;
+
+/// Frames further apart than this are assumed to be garbage
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// A fixed number of return addresses captured from the stack.
+///
+/// Relies on frame pointers, which are always enabled for the kernel
+/// target (see `x86_64-blog_os.json`). Unused entries are zero.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Backtrace<const N: usize> {
+    pub addresses: [usize; N],
+}
+
+impl<const N: usize> Backtrace<N> {
+    pub const fn empty() -> Self {
+        Backtrace { addresses: [0; N] }
+    }
+
+    /// Captures the return addresses of the calling function's callers,
+    /// skipping the `skip` innermost frames.
+    #[inline(always)]
+    pub fn capture(skip: usize) -> Self {
+        let mut skipped = 0;
+        Self::capture_skipping(|_| {
+            skipped += 1;
+            skipped <= skip
+        })
+    }
+
+    /// Captures the return addresses of the calling function's callers,
+    /// skipping the innermost frames as long as `skip` returns true for
+    /// their return address.
+    #[inline(always)]
+    pub fn capture_skipping(skip: impl FnMut(usize) -> bool) -> Self {
+        let rbp: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        unsafe { Self::from_frame_pointer(rbp, skip) }
+    }
+
+    /// Walks the frame pointer chain starting at `rbp`, see `capture_skipping`.
+    ///
+    /// Unsafe because `rbp` must point to a valid stack frame (or be zero).
+    pub unsafe fn from_frame_pointer(mut rbp: usize, mut skip: impl FnMut(usize) -> bool) -> Self {
+        let mut backtrace = Self::empty();
+        let mut index = 0;
+        let mut skipping = true;
+
+        while index < N && rbp != 0 && rbp % 8 == 0 {
+            // every frame starts with the caller's rbp, followed by the return address
+            let frame = rbp as *const usize;
+            let next = *frame;
+            let return_address = *frame.add(1);
+            if return_address == 0 {
+                break;
+            }
+
+            skipping = skipping && skip(return_address);
+            if !skipping {
+                backtrace.addresses[index] = return_address;
+                index += 1;
+            }
+
+            // the stack grows down, so callers always have higher frame addresses
+            if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+                break;
+            }
+            rbp = next;
+        }
+
+        backtrace
+    }
+
+    /// Iterates over the captured return addresses, innermost first
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.addresses.iter().copied().take_while(|&addr| addr != 0)
+    }
+}
+
+impl<const N: usize> fmt::Debug for Backtrace<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        for addr in self.iter() {
+            list.entry(&format_args!("{:#x}", addr));
+        }
+        list.finish()
+    }
+}
@@ -4,6 +4,7 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
 struct Selectors {
     code_selector: SegmentSelector,
@@ -23,6 +24,17 @@ lazy_static! {
             // x86_64 stacks grow downward so we give the top of the stack
             stack_end
         };
+        // Page faults get their own stack so that overflowing a kernel stack
+        // can be reported, and lazily mapped stack pages can be faulted in.
+        // A page fault in the page fault handler starts at the top again,
+        // `interrupts::page_fault` detects that and panics.
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
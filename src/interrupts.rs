@@ -1,12 +1,18 @@
 use crate::println;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::gdt;
-use crate::hlt_loop;
-use crate::print;
+use crate::memory::{cow, stack, user, vma};
+use exceptions::TrapFrame;
+use irq::IrqHandler;
+
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
+pub mod stats;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -17,87 +23,123 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // new
-        }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+pub fn init_idt() {
+    IDT.load();
+    irq::register_irq(irq::TIMER, IrqHandler::Fn(timer_interrupt))
+        .expect("timer IRQ already registered");
+    irq::register_irq(irq::KEYBOARD, IrqHandler::Fn(keyboard_interrupt))
+        .expect("keyboard IRQ already registered");
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
+/// The hardware that delivers external interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    /// The two chained 8259 PICs, used until `enable_apic` succeeds
+    Pic,
+    /// The local APIC and the I/O APICs
+    Apic,
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn controller() -> Controller {
+    if APIC_ACTIVE.load(Ordering::Relaxed) {
+        Controller::Apic
+    } else {
+        Controller::Pic
     }
 }
 
-pub fn init_idt() {
-    IDT.load();
+/// Switches interrupt delivery from the PICs to the APIC.
+///
+/// Returns false and keeps using the PICs if the system has no usable APIC.
+/// Requires `memory::init_kernel_memory` to have been called.
+pub fn enable_apic() -> bool {
+    match apic::init() {
+        Ok(()) => {
+            APIC_ACTIVE.store(true, Ordering::Relaxed);
+            irq::unmask_registered();
+            true
+        }
+        Err(err) => {
+            println!("APIC unavailable ({:?}), using the 8259 PIC", err);
+            false
+        }
+    }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+fn timer_interrupt() {
+    crate::time::tick();
+    crate::task::timer::advance();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    // error code is always 0 so we do not want to use it
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
+/// Number of page faults being handled.
+///
+/// Every page fault starts at the top of the same IST stack, so a page fault
+/// raised by the handler overwrites the frame of the one being handled.
+static PAGE_FAULT_DEPTH: AtomicUsize = AtomicUsize::new(0);
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/// Called by the exception handler for page faults
+fn page_fault(frame: &TrapFrame) {
+    use x86_64::registers::control::Cr2;
 
-    // Signify the end of the interrupt
-    // CPU can now accept new interrupts
-    // Need to be careful to send the correct clear signal as we could potentially clear other very important interrupts
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    let addr = Cr2::read();
+    if PAGE_FAULT_DEPTH.fetch_add(1, Ordering::SeqCst) > 0 {
+        // the interrupted handler can not be resumed
+        panic!(
+            "EXCEPTION: PAGE FAULT while handling a page fault\nAccessed Address: {:?}\n{}",
+            addr, frame
+        );
     }
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    // SMEP and SMAP violations are always fatal, writes to copy-on-write
+    // pages get their own copy, faults in lazily backed regions are
+    // resolved by mapping a frame
+    let result =
+        user::check_supervisor_access(addr, error_code, &frame.stack_frame).and_then(|()| {
+            if error_code.contains(
+                PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+            ) {
+                cow::handle_write_fault(addr)
+            } else {
+                vma::handle_page_fault(addr, error_code)
+            }
+        });
+    if let Err(err) = result {
+        if let Some(id) = stack::overflowed_stack(addr) {
+            panic!(
+                "EXCEPTION: PAGE FAULT (stack overflow in stack {})\nAccessed Address: {:?}\n{}",
+                id, addr, frame
+            );
+        }
+        panic!(
+            "EXCEPTION: PAGE FAULT ({})\nAccessed Address: {:?}\n{}",
+            err, addr, frame
+        );
+    }
+    PAGE_FAULT_DEPTH.fetch_sub(1, Ordering::SeqCst);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt() {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+/// Raised by the local APIC when an interrupt disappears before it was
+/// delivered, must not be acknowledged with an EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    stats::record_spurious(stats::SpuriousSource::Apic);
 }
 
 #[test_case]
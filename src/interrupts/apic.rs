@@ -0,0 +1,266 @@
+use super::PIC_1_OFFSET;
+use crate::acpi::{self, Madt};
+use crate::memory;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    mapper::MapToError, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Start of the virtual region the APIC registers are mapped to, in the
+/// kernel regions
+const MMIO_START: u64 = 0x_ffff_c077_0000_0000;
+
+/// Vector the local APIC raises for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Number of legacy ISA IRQs
+const ISA_IRQS: u8 = 16;
+
+// model specific register holding the local APIC base address
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS: u64 = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// I/O APIC registers, accessed through a select and a data register
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+const MAX_IO_APICS: usize = 4;
+
+/// Virtual address of the local APIC registers, zero while it is disabled.
+///
+/// Not behind a lock so that interrupt handlers can always send their EOI.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+
+/// The interrupt controller information the I/O APICs were programmed with
+static MADT: Mutex<Option<Madt>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// The CPU has no local APIC
+    Unsupported,
+    /// There is no MADT or it lists no I/O APIC
+    NoIoApic,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ApicError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ApicError::Map(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    /// Number of interrupts handled by this I/O APIC
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+        ptr::read_volatile((self.base + 0x10u64).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+        ptr::write_volatile((self.base + 0x10u64).as_mut_ptr::<u32>(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    unsafe fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // mask first so that a half written entry never fires
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Returns true once `init` succeeded
+pub fn enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Maps the page holding the registers at `phys` to the `index`th page of
+/// the APIC region and returns the virtual address of the registers
+fn map_registers(index: u64, phys: PhysAddr) -> Result<VirtAddr, ApicError> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+        MMIO_START + index * Size4KiB::SIZE,
+    ));
+    let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+
+    memory::with_kernel_memory(|kernel_memory| unsafe {
+        kernel_memory
+            .mapper
+            .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)
+            .map(|flush| flush.flush())
+    })?;
+    Ok(page.start_address() + (phys.as_u64() - frame.start_address().as_u64()))
+}
+
+unsafe fn lapic_read(register: u64) -> u32 {
+    ptr::read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + register) as *const u32)
+}
+
+unsafe fn lapic_write(register: u64, value: u32) {
+    ptr::write_volatile((LAPIC_BASE.load(Ordering::Relaxed) + register) as *mut u32, value);
+}
+
+/// Masks every interrupt of both 8259 PICs
+fn disable_pics() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// Switches from the 8259 PICs to the local APIC and the I/O APICs.
+///
+/// Legacy IRQ `n` keeps its vector `PIC_1_OFFSET + n`. All IRQs stay masked
+/// until `set_irq_masked` is called. Requires `memory::init_kernel_memory`
+/// to have been called.
+pub fn init() -> Result<(), ApicError> {
+    if enabled() {
+        return Ok(());
+    }
+    // CPUID leaf 1, EDX bit 9 reports an on-chip APIC
+    if unsafe { __cpuid(1) }.edx & (1 << 9) == 0 {
+        return Err(ApicError::Unsupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoIoApic)?;
+    if madt.io_apics.iter().all(Option::is_none) {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let lapic_base = map_registers(0, madt.local_apic_address)?;
+    let mut io_apics = [None; MAX_IO_APICS];
+    for (index, info) in madt.io_apics.iter().enumerate() {
+        if let Some(info) = info {
+            let base = map_registers(index as u64 + 1, info.address)?;
+            let mut io_apic = IoApic {
+                base,
+                gsi_base: info.gsi_base,
+                entries: 0,
+            };
+            // bits 16..24 hold the index of the last redirection entry
+            io_apic.entries = ((unsafe { io_apic.read(IOAPIC_VERSION) } >> 16) & 0xff) + 1;
+            io_apics[index] = Some(io_apic);
+        }
+    }
+
+    without_interrupts(|| {
+        disable_pics();
+
+        unsafe {
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | APIC_BASE_ENABLE);
+        }
+        LAPIC_BASE.store(lapic_base.as_u64(), Ordering::Relaxed);
+        unsafe {
+            lapic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+            lapic_write(LAPIC_TASK_PRIORITY, 0);
+        }
+        let lapic_id = u64::from(unsafe { lapic_read(LAPIC_ID) } >> 24);
+
+        for io_apic in io_apics.iter().flatten() {
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                unsafe { io_apic.set_redirection(gsi, REDIRECTION_MASKED) };
+            }
+        }
+
+        // IRQ 2 is the cascade of the PICs and never raised by the I/O APIC
+        for irq in (0..ISA_IRQS).filter(|&irq| irq != 2) {
+            let route = madt.isa_irq(irq);
+            let io_apic = match io_apics.iter().flatten().find(|a| a.handles(route.gsi)) {
+                Some(io_apic) => io_apic,
+                None => continue,
+            };
+
+            let mut entry =
+                u64::from(PIC_1_OFFSET + irq) | lapic_id << 56 | REDIRECTION_MASKED;
+            if route.active_low {
+                entry |= REDIRECTION_ACTIVE_LOW;
+            }
+            if route.level_triggered {
+                entry |= REDIRECTION_LEVEL_TRIGGERED;
+            }
+            unsafe { io_apic.set_redirection(route.gsi, entry) };
+        }
+
+        *IO_APICS.lock() = io_apics;
+        *MADT.lock() = Some(madt);
+    });
+    Ok(())
+}
+
+/// Signals the end of the current interrupt to the local APIC
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// Masks or unmasks the legacy ISA `irq` at the I/O APIC.
+///
+/// Returns false if the APIC is not enabled or `irq` is not routed.
+pub fn set_irq_masked(irq: u8, masked: bool) -> bool {
+    without_interrupts(|| {
+        let gsi = match MADT.lock().as_ref() {
+            Some(madt) if irq < ISA_IRQS && irq != 2 => madt.isa_irq(irq).gsi,
+            _ => return false,
+        };
+        let io_apics = IO_APICS.lock();
+        let io_apic = match io_apics.iter().flatten().find(|a| a.handles(gsi)) {
+            Some(io_apic) => io_apic,
+            None => return false,
+        };
+
+        unsafe {
+            let entry = io_apic.redirection(gsi);
+            let entry = if masked {
+                entry | REDIRECTION_MASKED
+            } else {
+                entry & !REDIRECTION_MASKED
+            };
+            io_apic.set_redirection(gsi, entry);
+        }
+        true
+    })
+}
+
+/// Returns the MADT the APIC was set up from, `None` while it is disabled
+pub fn madt() -> Option<Madt> {
+    without_interrupts(|| *MADT.lock())
+}
@@ -0,0 +1,364 @@
+use super::stats;
+use crate::{gdt, println};
+use core::arch::global_asm;
+use core::{fmt, mem};
+#[cfg(feature = "test-hooks")]
+use spin::Mutex;
+#[cfg(feature = "test-hooks")]
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{
+    Entry, HandlerFuncWithErrCode, InterruptDescriptorTable, InterruptStackFrameValue,
+    PageFaultErrorCode,
+};
+
+// Entry stubs for the exceptions. They save the general purpose registers
+// on top of the frame pushed by the CPU, so that `blog_os_exception_dispatch` sees the
+// registers of the faulting code and can change them before returning.
+//
+// Stubs of exceptions without an error code push a zero instead, so that
+// every `TrapFrame` has the same layout. The CPU aligned the stack before
+// pushing its frame, the 22 quadwords on top of it keep it aligned for the
+// call. `add rsp, 16` drops the vector and error code before returning.
+global_asm!(
+    r#"
+.macro EXCEPTION_STUB vector
+.global blog_os_exception_\vector
+blog_os_exception_\vector:
+    push 0
+    push \vector
+    jmp blog_os_exception_common
+.endm
+
+.macro EXCEPTION_STUB_ERROR_CODE vector
+.global blog_os_exception_\vector
+blog_os_exception_\vector:
+    push \vector
+    jmp blog_os_exception_common
+.endm
+
+EXCEPTION_STUB 0
+EXCEPTION_STUB 1
+EXCEPTION_STUB 2
+EXCEPTION_STUB 3
+EXCEPTION_STUB 4
+EXCEPTION_STUB 5
+EXCEPTION_STUB 6
+EXCEPTION_STUB 7
+EXCEPTION_STUB_ERROR_CODE 8
+EXCEPTION_STUB_ERROR_CODE 10
+EXCEPTION_STUB_ERROR_CODE 11
+EXCEPTION_STUB_ERROR_CODE 12
+EXCEPTION_STUB_ERROR_CODE 13
+EXCEPTION_STUB_ERROR_CODE 14
+EXCEPTION_STUB 16
+EXCEPTION_STUB_ERROR_CODE 17
+EXCEPTION_STUB 18
+EXCEPTION_STUB 19
+EXCEPTION_STUB 20
+EXCEPTION_STUB_ERROR_CODE 21
+EXCEPTION_STUB_ERROR_CODE 30
+
+blog_os_exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call blog_os_exception_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+"#
+);
+
+/// General purpose registers of the interrupted code, in the order the stubs push them
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Everything saved on the stack when an exception is raised
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    /// Zero for exceptions without an error code
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+/// Called for every exception before the default handling.
+///
+/// Returning true resumes the interrupted code with the (possibly changed)
+/// frame. Lets tests trigger exceptions and skip the faulting instruction.
+#[cfg(feature = "test-hooks")]
+pub type ExceptionHook = fn(&mut TrapFrame) -> bool;
+
+#[cfg(feature = "test-hooks")]
+static HOOK: Mutex<Option<ExceptionHook>> = Mutex::new(None);
+
+/// Only available with the `test-hooks` feature
+#[cfg(feature = "test-hooks")]
+pub fn set_hook(hook: Option<ExceptionHook>) {
+    without_interrupts(|| *HOOK.lock() = hook);
+}
+
+/// Handled by `interrupts::page_fault` after the hook
+pub const PAGE_FAULT: u64 = 14;
+/// Raised for control flow violations once CET is enabled
+pub const CONTROL_PROTECTION: u64 = 21;
+
+/// Size of a gate descriptor in the IDT
+const IDT_ENTRY_SIZE: usize = 16;
+
+/// Returns the name of the exception with the given vector
+pub fn name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        2 => "NON-MASKABLE INTERRUPT",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "X87 FLOATING POINT",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        20 => "VIRTUALIZATION",
+        21 => "CONTROL PROTECTION",
+        30 => "SECURITY EXCEPTION",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Table a selector error code refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// Error code of exceptions caused by loading a segment or gate descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode {
+    /// The exception happened while delivering an external event
+    pub external: bool,
+    pub table: DescriptorTable,
+    pub index: u16,
+}
+
+impl From<u64> for SelectorErrorCode {
+    fn from(error_code: u64) -> Self {
+        let table = match (error_code >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        };
+        SelectorErrorCode {
+            external: error_code & 1 != 0,
+            table,
+            index: ((error_code >> 3) & 0x1fff) as u16,
+        }
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} index {}", self.table, self.index)?;
+        if self.external {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns true for the exceptions whose error code is a `SelectorErrorCode`
+pub fn has_selector_error_code(vector: u64) -> bool {
+    matches!(vector, 10..=13)
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registers = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            let separator = if i % 3 == 2 { "\n" } else { "  " };
+            write!(f, "{:>3}={:#018x}{}", name, value, separator)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error Code: {:#x}", self.error_code)?;
+        if has_selector_error_code(self.vector) && self.error_code != 0 {
+            write!(f, " ({})", SelectorErrorCode::from(self.error_code))?;
+        } else if self.vector == PAGE_FAULT {
+            let error_code = PageFaultErrorCode::from_bits_truncate(self.error_code);
+            write!(f, " ({:?})", error_code)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:#?}", self.stack_frame)?;
+        write!(f, "{}", self.registers)?;
+
+        let (level_4_table, _) = Cr3::read();
+        write!(
+            f,
+            "cr0={:#x}  cr2={:#x}  cr3={:#x}  cr4={:#x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            level_4_table.start_address().as_u64(),
+            Cr4::read_raw()
+        )
+    }
+}
+
+#[no_mangle]
+extern "C" fn blog_os_exception_dispatch(frame: &mut TrapFrame) {
+    stats::measure(frame.vector as u8, || handle(frame));
+}
+
+fn handle(frame: &mut TrapFrame) {
+    // the lock is only held by `set_hook`, which cannot fault
+    #[cfg(feature = "test-hooks")]
+    if let Some(hook) = HOOK.try_lock().and_then(|hook| *hook) {
+        if hook(frame) {
+            return;
+        }
+    }
+
+    match frame.vector {
+        PAGE_FAULT => super::page_fault(frame),
+        // traps, execution continues after the instruction
+        1 | 3 => println!("EXCEPTION: {}\n{:#?}", name(frame.vector), frame.stack_frame),
+        2 => println!("EXCEPTION: {}\n{}", name(frame.vector), frame),
+        _ => panic!("EXCEPTION: {}\n{}", name(frame.vector), frame),
+    }
+}
+
+/// Points `$entry` at the stub `$stub`.
+///
+/// The stubs do not follow the "x86-interrupt" calling convention that
+/// `set_handler_fn` expects, but only their address ends up in the IDT.
+macro_rules! set_stub {
+    ($entry:expr, $stub:ident) => {{
+        extern "C" {
+            fn $stub();
+        }
+        $entry.set_handler_fn(unsafe { core::mem::transmute($stub as unsafe extern "C" fn()) })
+    }};
+}
+
+/// Installs the handlers of all exceptions
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    set_stub!(idt.divide_error, blog_os_exception_0);
+    set_stub!(idt.debug, blog_os_exception_1);
+    set_stub!(idt.non_maskable_interrupt, blog_os_exception_2);
+    set_stub!(idt.breakpoint, blog_os_exception_3);
+    set_stub!(idt.overflow, blog_os_exception_4);
+    set_stub!(idt.bound_range_exceeded, blog_os_exception_5);
+    set_stub!(idt.invalid_opcode, blog_os_exception_6);
+    set_stub!(idt.device_not_available, blog_os_exception_7);
+    let double_fault = set_stub!(idt.double_fault, blog_os_exception_8);
+    unsafe { double_fault.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX) };
+    set_stub!(idt.invalid_tss, blog_os_exception_10);
+    set_stub!(idt.segment_not_present, blog_os_exception_11);
+    set_stub!(idt.stack_segment_fault, blog_os_exception_12);
+    set_stub!(idt.general_protection_fault, blog_os_exception_13);
+    let page_fault = set_stub!(idt.page_fault, blog_os_exception_14);
+    unsafe { page_fault.set_stack_index(gdt::PAGE_FAULT_IST_INDEX) };
+    set_stub!(idt.x87_floating_point, blog_os_exception_16);
+    set_stub!(idt.alignment_check, blog_os_exception_17);
+    set_stub!(idt.machine_check, blog_os_exception_18);
+    set_stub!(idt.simd_floating_point, blog_os_exception_19);
+    set_stub!(idt.virtualization, blog_os_exception_20);
+    set_stub!(control_protection(idt), blog_os_exception_21);
+    set_stub!(idt.security_exception, blog_os_exception_30);
+}
+
+/// Returns the IDT entry of the control protection exception.
+///
+/// The `InterruptDescriptorTable` of x86_64 0.14 keeps this vector in a
+/// private reserved entry. The table has the layout the CPU expects, one
+/// gate descriptor per vector, which is checked against the public entries
+/// around it.
+fn control_protection(idt: &mut InterruptDescriptorTable) -> &mut Entry<HandlerFuncWithErrCode> {
+    let base = idt as *mut InterruptDescriptorTable as usize;
+    let offset_of = |entry: *const Entry<HandlerFuncWithErrCode>| entry as usize - base;
+
+    let entry_size = mem::size_of::<Entry<HandlerFuncWithErrCode>>();
+    assert_eq!(entry_size, IDT_ENTRY_SIZE);
+    assert_eq!(offset_of(&idt.alignment_check), 17 * IDT_ENTRY_SIZE);
+    assert_eq!(offset_of(&idt.security_exception), 30 * IDT_ENTRY_SIZE);
+
+    let entry = base + CONTROL_PROTECTION as usize * IDT_ENTRY_SIZE;
+    unsafe { &mut *(entry as *mut Entry<HandlerFuncWithErrCode>) }
+}
@@ -0,0 +1,259 @@
+use super::stats::{self, SpuriousSource};
+use super::{apic, controller, Controller, PICS, PIC_1_OFFSET};
+use alloc::boxed::Box;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+/// Number of legacy IRQ lines
+pub const IRQ_COUNT: u8 = 16;
+
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+/// Connects the second PIC to the first one, never raised itself
+const CASCADE: u8 = 2;
+
+/// Code run for an IRQ, in interrupt context with interrupts disabled.
+///
+/// Must not block or allocate. The end of interrupt is sent after it returns.
+pub enum IrqHandler {
+    Fn(fn()),
+    /// Needs the heap to be initialized
+    Closure(Box<dyn FnMut() + Send>),
+}
+
+impl IrqHandler {
+    pub fn closure(closure: impl FnMut() + Send + 'static) -> Self {
+        IrqHandler::Closure(Box::new(closure))
+    }
+
+    fn call(&mut self) {
+        match self {
+            IrqHandler::Fn(handler) => handler(),
+            IrqHandler::Closure(handler) => handler(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ is out of range or the cascade
+    InvalidIrq,
+    AlreadyRegistered,
+}
+
+#[allow(clippy::declare_interior_mutable_const)] // only used to initialize `HANDLERS`
+const NO_HANDLER: Mutex<Option<IrqHandler>> = Mutex::new(None);
+
+/// Only locked with interrupts disabled, so the trampolines never find a
+/// handler locked
+static HANDLERS: [Mutex<Option<IrqHandler>>; IRQ_COUNT as usize] =
+    [NO_HANDLER; IRQ_COUNT as usize];
+
+/// Returns the interrupt vector of `irq`
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Returns the device the IRQ is usually wired to on a PC
+pub fn name(irq: u8) -> &'static str {
+    match irq {
+        0 => "timer",
+        1 => "keyboard",
+        2 => "cascade",
+        3 => "COM2",
+        4 => "COM1",
+        5 => "LPT2",
+        6 => "floppy",
+        7 => "LPT1",
+        8 => "rtc",
+        9 => "acpi",
+        12 => "mouse",
+        13 => "fpu",
+        14 => "primary ATA",
+        15 => "secondary ATA",
+        _ => "",
+    }
+}
+
+fn check_irq(irq: u8) -> Result<(), IrqError> {
+    if irq >= IRQ_COUNT || irq == CASCADE {
+        return Err(IrqError::InvalidIrq);
+    }
+    Ok(())
+}
+
+/// Runs `handler` whenever `irq` is raised and unmasks the line.
+///
+/// The handler must not register or unregister its own IRQ.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_irq(irq)?;
+    without_interrupts(|| {
+        let mut slot = HANDLERS[usize::from(irq)].lock();
+        if slot.is_some() {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        *slot = Some(handler);
+        set_masked(irq, false);
+        Ok(())
+    })
+}
+
+/// Masks `irq` and removes its handler
+pub fn unregister_irq(irq: u8) -> Option<IrqHandler> {
+    check_irq(irq).ok()?;
+    without_interrupts(|| {
+        set_masked(irq, true);
+        HANDLERS[usize::from(irq)].lock().take()
+    })
+}
+
+pub fn is_registered(irq: u8) -> bool {
+    check_irq(irq).is_ok() && without_interrupts(|| HANDLERS[usize::from(irq)].lock().is_some())
+}
+
+/// Stops the interrupt controller from raising `irq`, the handler stays registered
+pub fn mask_irq(irq: u8) {
+    if check_irq(irq).is_ok() {
+        without_interrupts(|| set_masked(irq, true));
+    }
+}
+
+/// Lets the interrupt controller raise `irq` again
+pub fn unmask_irq(irq: u8) {
+    if check_irq(irq).is_ok() {
+        without_interrupts(|| set_masked(irq, false));
+    }
+}
+
+/// Unmasks every IRQ with a handler, after switching the interrupt controller
+pub(super) fn unmask_registered() {
+    without_interrupts(|| {
+        for irq in (0..IRQ_COUNT).filter(|&irq| irq != CASCADE) {
+            if HANDLERS[usize::from(irq)].lock().is_some() {
+                set_masked(irq, false);
+            }
+        }
+    });
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    match controller() {
+        Controller::Apic => {
+            apic::set_irq_masked(irq, masked);
+        }
+        Controller::Pic => {
+            // IRQs of the second PIC only arrive while the cascade is unmasked
+            if irq >= 8 && !masked {
+                set_pic_masked(CASCADE, false);
+            }
+            set_pic_masked(irq, masked);
+        }
+    }
+}
+
+fn set_pic_masked(irq: u8, masked: bool) {
+    let mut port = Port::<u8>::new(if irq < 8 { 0x21 } else { 0xa1 });
+    let bit = 1 << (irq % 8);
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | bit } else { mask & !bit });
+    }
+}
+
+/// Returns true if the PIC raised `irq` without a device asking for it.
+///
+/// A PIC reports IRQ 7 (or 15 for the second one) when a request went away
+/// before it was acknowledged. Its in-service bit is not set then, and it
+/// must not get an end of interrupt, which would end another IRQ instead.
+fn is_spurious(irq: u8) -> bool {
+    if controller() != Controller::Pic || (irq != 7 && irq != 15) {
+        return false;
+    }
+
+    let mut command = Port::<u8>::new(if irq < 8 { 0x20 } else { 0xa0 });
+    let in_service = unsafe {
+        // OCW3, read the in-service register
+        command.write(0x0b);
+        command.read()
+    };
+    if in_service & 0x80 != 0 {
+        return false;
+    }
+
+    // the first PIC did deliver the cascade and waits for its end of interrupt
+    if irq == 15 {
+        unsafe { Port::<u8>::new(0x20).write(0x20) };
+    }
+    true
+}
+
+/// Signals the end of the interrupt to whichever controller delivered it.
+///
+/// The controller holds back further interrupts of the same or lower
+/// priority until then.
+fn end_of_interrupt(irq: u8) {
+    match controller() {
+        Controller::Apic => apic::end_of_interrupt(),
+        Controller::Pic => unsafe { PICS.lock().notify_end_of_interrupt(vector(irq)) },
+    }
+}
+
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        let source = if irq < 8 {
+            SpuriousSource::FirstPic
+        } else {
+            SpuriousSource::SecondPic
+        };
+        stats::record_spurious(source);
+        return;
+    }
+
+    stats::measure(vector(irq), || {
+        if let Some(handler) = HANDLERS[usize::from(irq)].lock().as_mut() {
+            handler.call();
+        }
+    });
+    end_of_interrupt(irq);
+}
+
+macro_rules! trampolines {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// Entry of every IRQ vector, forwards to `dispatch` with the IRQ number
+        const TRAMPOLINES: [HandlerFunc; IRQ_COUNT as usize] = [$($name),*];
+    };
+}
+
+trampolines!(
+    0 => irq_0,
+    1 => irq_1,
+    2 => irq_2,
+    3 => irq_3,
+    4 => irq_4,
+    5 => irq_5,
+    6 => irq_6,
+    7 => irq_7,
+    8 => irq_8,
+    9 => irq_9,
+    10 => irq_10,
+    11 => irq_11,
+    12 => irq_12,
+    13 => irq_13,
+    14 => irq_14,
+    15 => irq_15,
+);
+
+/// Points the vectors of all IRQs at their trampolines
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (irq, trampoline) in (0..IRQ_COUNT).zip(TRAMPOLINES) {
+        idt[usize::from(vector(irq))].set_handler_fn(trampoline);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{exceptions, irq};
+use crate::time;
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const VECTORS: usize = 256;
+
+#[allow(clippy::declare_interior_mutable_const)] // only used to initialize the counters
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static DELIVERIES: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+/// TSC cycles spent in the handler of every vector
+static CYCLES: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static SPURIOUS: [AtomicU64; 3] = [ZERO; 3];
+
+/// Where a spurious interrupt came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpuriousSource {
+    /// IRQ 7 without its in-service bit
+    FirstPic,
+    /// IRQ 15 without its in-service bit
+    SecondPic,
+    /// The spurious vector of the local APIC
+    Apic,
+}
+
+/// Counters of one interrupt vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorStats {
+    pub deliveries: u64,
+    /// Total TSC cycles spent in the handler
+    pub cycles: u64,
+}
+
+impl VectorStats {
+    pub fn average_cycles(&self) -> u64 {
+        self.cycles.checked_div(self.deliveries).unwrap_or(0)
+    }
+}
+
+/// Counts a delivery of `vector` and adds the time `f` takes to its total.
+///
+/// The delivery is counted before `f` runs, so handlers that panic show up too.
+pub(super) fn measure<R>(vector: u8, f: impl FnOnce() -> R) -> R {
+    DELIVERIES[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+    let start = unsafe { _rdtsc() };
+    let result = f();
+    let cycles = unsafe { _rdtsc() }.wrapping_sub(start);
+    CYCLES[usize::from(vector)].fetch_add(cycles, Ordering::Relaxed);
+    result
+}
+
+pub(super) fn record_spurious(source: SpuriousSource) {
+    SPURIOUS[source as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn vector_stats(vector: u8) -> VectorStats {
+    VectorStats {
+        deliveries: DELIVERIES[usize::from(vector)].load(Ordering::Relaxed),
+        cycles: CYCLES[usize::from(vector)].load(Ordering::Relaxed),
+    }
+}
+
+/// Number of spurious interrupts from `source`, they are not counted as deliveries
+pub fn spurious(source: SpuriousSource) -> u64 {
+    SPURIOUS[source as usize].load(Ordering::Relaxed)
+}
+
+/// Sets all counters back to zero
+pub fn reset() {
+    for counter in DELIVERIES.iter().chain(&CYCLES).chain(&SPURIOUS) {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Writes what the handler of `vector` is for
+fn describe(vector: u8, f: &mut fmt::Formatter) -> fmt::Result {
+    let first_irq = irq::vector(0);
+    match vector {
+        0..=31 => write!(f, "{}", exceptions::name(u64::from(vector))),
+        v if (first_irq..first_irq + irq::IRQ_COUNT).contains(&v) => {
+            let irq = v - first_irq;
+            write!(f, "IRQ {:<2} {}", irq, irq::name(irq))
+        }
+        super::apic::SPURIOUS_VECTOR => write!(f, "APIC spurious"),
+        _ => Ok(()),
+    }
+}
+
+/// Every vector that was delivered at least once, in the style of `/proc/interrupts`
+struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tsc_frequency = time::tsc_frequency();
+        write!(f, "VEC      COUNT  AVG CYCLES    TOTAL US  DESCRIPTION")?;
+        for vector in 0..=u8::MAX {
+            let stats = vector_stats(vector);
+            if stats.deliveries == 0 {
+                continue;
+            }
+            let total_micros = (u128::from(stats.cycles) * 1_000_000)
+                .checked_div(u128::from(tsc_frequency))
+                .unwrap_or(0);
+            write!(
+                f,
+                "\n{:>3} {:>10} {:>11} {:>11}  ",
+                vector,
+                stats.deliveries,
+                stats.average_cycles(),
+                total_micros
+            )?;
+            describe(vector, f)?;
+        }
+        write!(
+            f,
+            "\nSPU {:>10}  PIC IRQ 7: {}, PIC IRQ 15: {}, APIC: {}",
+            SPURIOUS.iter().map(|count| count.load(Ordering::Relaxed)).sum::<u64>(),
+            spurious(SpuriousSource::FirstPic),
+            spurious(SpuriousSource::SecondPic),
+            spurious(SpuriousSource::Apic)
+        )
+    }
+}
+
+/// The delivery count and handler time of every vector, ready to be
+/// printed wherever the caller wants
+pub fn report() -> impl fmt::Display {
+    Report
+}
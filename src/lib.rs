@@ -9,21 +9,29 @@
 #![feature(alloc_error_handler)]
 // Needed for mutable const references in linked list allocator
 #![feature(const_mut_refs)]
+// needed for collections placed in an arena
+#![feature(allocator_api)]
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod rtc;
 pub mod serial;
 pub mod task;
+pub mod test_support;
+pub mod time;
 pub mod vga_buffer;
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
+use bootloader::BootInfo;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
@@ -34,7 +42,10 @@ entry_point!(test_kernel_main);
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    memory::user::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
+    rtc::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -80,6 +91,21 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     exit_qemu(QemuExitCode::Success);
 }
 
+/// Initializes the kernel, its memory management and the heap like
+/// `kernel_main` does, for tests that need more than `init`
+pub fn test_init(boot_info: &'static BootInfo) {
+    use memory::buddy::BuddyFrameAllocator;
+    use x86_64::VirtAddr;
+
+    init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+}
+
 // Usable by other tests
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
@@ -127,5 +153,6 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 /// Heap Allocation
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    allocator::print_meminfo();
     panic!("allocation error: {:?}", layout)
 }
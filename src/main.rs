@@ -44,7 +44,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // We will exit and not return so `!` is appropriate
     // Use C calling convention & default entry point `_start`
     use blog_os::allocator;
-    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use blog_os::memory::{self, buddy::BuddyFrameAllocator};
     use x86_64::VirtAddr;
 
     println!("Hello World{}", "!");
@@ -54,10 +54,14 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     // Heap allocation
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    // route interrupts through the APIC if there is one
+    blog_os::interrupts::enable_apic();
 
     #[cfg(test)]
     test_main();
@@ -1,15 +1,124 @@
 use x86_64::{
+    align_down, align_up,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::{MappedFrame, Translate, TranslateResult},
+        page_table::PageTableEntry,
+        FrameAllocator, OffsetPageTable, PageSize, PageTable, PageTableFlags, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 
+use crate::{println, serial_println};
+use buddy::BuddyFrameAllocator;
+
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod stack;
+pub mod user;
+pub mod vma;
+
+/// Virtual address at which the bootloader mapped the complete physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Where the bootloader maps the physical memory, set in `Cargo.toml`.
+///
+/// Known at compile time so the VGA buffer can be used before `init`.
+pub const PHYSICAL_MEMORY_START: u64 = 0xffff_8000_0000_0000;
+
+/// Initializes the kernel page table.
+///
+/// Also turns on no-execute support and write protection for the kernel,
+/// and removes execute permission from everything but the kernel code, see
+/// `enforce_write_xor_execute`.
 pub unsafe fn init(physical_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    assert_eq!(
+        physical_mem_offset.as_u64(),
+        PHYSICAL_MEMORY_START,
+        "physical memory mapped at an unexpected offset"
+    );
+    PHYSICAL_MEMORY_OFFSET.store(physical_mem_offset.as_u64(), Ordering::Relaxed);
+    enable_protection();
+
     let level_4_table = active_level4_table(physical_mem_offset);
-    OffsetPageTable::new(level_4_table, physical_mem_offset)
+    let mut mapper = OffsetPageTable::new(level_4_table, physical_mem_offset);
+    enforce_write_xor_execute(&mut mapper);
+    mapper
+}
+
+/// Sets EFER.NXE so that `NO_EXECUTE` can be used, and CR0.WP so that the
+/// kernel cannot write to read-only pages either
+fn enable_protection() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
+
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    }
+}
+
+/// Returns the offset passed to `init`
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/////////////////////////////////////////////
+// Kernel memory
+
+/// The page table and frame allocator used by the kernel after boot.
+///
+/// Everything that needs to map memory at runtime (e.g. a growing heap)
+/// goes through this instead of keeping its own references.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// Hands the mapper and frame allocator over to the kernel.
+///
+/// Should be called once, after `allocator::init_heap`.
+pub fn init_kernel_memory(mapper: OffsetPageTable<'static>, frame_allocator: BuddyFrameAllocator) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory {
+            mapper,
+            frame_allocator,
+        });
+    });
+}
+
+/// Runs `f` with exclusive access to the kernel memory.
+///
+/// Panics if `init_kernel_memory` was not called yet.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> R {
+    // interrupt handlers may need the kernel memory as well, so they must
+    // not run while we hold the lock
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        f(kernel_memory
+            .as_mut()
+            .expect("kernel memory not initialized"))
+    })
+}
+
+/// Like `with_kernel_memory`, but returns `None` instead of blocking when the
+/// kernel memory is already locked or not initialized yet.
+///
+/// Used from contexts that could be running while the lock is held, such as
+/// the heap allocator or exception handlers.
+pub fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.try_lock()?;
+        kernel_memory.as_mut().map(f)
+    })
 }
 
 pub unsafe fn active_level4_table(physical_mem_offset: VirtAddr) -> &'static mut PageTable {
@@ -24,70 +133,351 @@ pub unsafe fn active_level4_table(physical_mem_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-pub unsafe fn translate_addr(addr: VirtAddr, physical_mem_offset: VirtAddr) -> Option<PhysAddr> {
-    translate_addr_inner(addr, physical_mem_offset)
+/// Size of the page that maps a translated address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
 }
 
-fn translate_addr_inner(addr: VirtAddr, physical_mem_offset: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::registers::control::Cr3;
-    use x86_64::structures::paging::page_table::FrameError;
+impl MappedPageSize {
+    /// Size of the page in bytes
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappedPageSize::Size4KiB => Size4KiB::SIZE,
+            MappedPageSize::Size2MiB => Size2MiB::SIZE,
+            MappedPageSize::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+}
 
-    let (level_4_table_frame, _) = Cr3::read();
+/// The result of translating a virtual address
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    pub phys_addr: PhysAddr,
+    pub page_size: MappedPageSize,
+    /// Flags of the lowest level entry, the one that maps the page
+    pub flags: PageTableFlags,
+}
+
+/// Translates the given virtual address to the physical address it is mapped to.
+///
+/// Works for 4 KiB pages as well as 2 MiB and 1 GiB huge pages.
+/// Returns `None` if the address is not mapped.
+pub fn translate(mapper: &impl Translate, addr: VirtAddr) -> Option<Translation> {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => {
+            let page_size = match frame {
+                MappedFrame::Size4KiB(_) => MappedPageSize::Size4KiB,
+                MappedFrame::Size2MiB(_) => MappedPageSize::Size2MiB,
+                MappedFrame::Size1GiB(_) => MappedPageSize::Size1GiB,
+            };
 
-    let table_indexes = [
-        addr.p4_index(),
-        addr.p3_index(),
-        addr.p2_index(),
-        addr.p1_index(),
-    ];
+            Some(Translation {
+                phys_addr: frame.start_address() + offset,
+                page_size,
+                flags,
+            })
+        }
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    }
+}
 
-    let mut frame = level_4_table_frame;
+/////////////////////////////////////////////
+// Introspection
 
-    for &index in &table_indexes {
-        let virt = physical_mem_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe { &*table_ptr };
+impl fmt::Display for MappedPageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MappedPageSize::Size4KiB => write!(f, "4KiB"),
+            MappedPageSize::Size2MiB => write!(f, "2MiB"),
+            MappedPageSize::Size1GiB => write!(f, "1GiB"),
+        }
+    }
+}
 
-        let entry = &table[index];
+/// Maximum number of distinct region types that are totalled
+const MAX_REGION_TYPES: usize = 16;
 
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("Huge frames are not supported"),
-        };
+/// Prints the memory regions reported by the bootloader, the total size of
+/// every region type and the frame usage of the kernel frame allocator
+pub fn print_memory_map(memory_map: &MemoryMap) {
+    let mut totals = [None::<(MemoryRegionType, u64)>; MAX_REGION_TYPES];
+
+    println!("Memory map:");
+    serial_println!("Memory map:");
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        println!("  {:#012x}-{:#012x} {:?}", start, end, region.region_type);
+        serial_println!("  {:#012x}-{:#012x} {:?}", start, end, region.region_type);
+
+        let size = end - start;
+        let existing = totals
+            .iter_mut()
+            .flatten()
+            .find(|(region_type, _)| *region_type == region.region_type);
+        if let Some((_, total)) = existing {
+            *total += size;
+        } else if let Some(slot) = totals.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((region.region_type, size));
+        }
+    }
+
+    println!("Totals:");
+    serial_println!("Totals:");
+    for (region_type, size) in totals.iter().flatten() {
+        println!("  {:?}: {} KiB", region_type, size / 1024);
+        serial_println!("  {:?}: {} KiB", region_type, size / 1024);
     }
 
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    let frames = try_with_kernel_memory(|kernel_memory| {
+        let frame_allocator = &kernel_memory.frame_allocator;
+        (frame_allocator.used_frames(), frame_allocator.free_frames())
+    });
+    if let Some((used, free)) = frames {
+        println!("Frames: {} used, {} free", used, free);
+        serial_println!("Frames: {} used, {} free", used, free);
+    }
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// Calls `f` with the virtual address, physical address, page size and
+/// flags of every page mapped by the given level 4 table, in address order.
+pub fn for_each_mapping(
+    level_4_table: &mut PageTable,
+    mut f: impl FnMut(VirtAddr, PhysAddr, MappedPageSize, PageTableFlags),
+) {
+    for_each_entry(level_4_table, |virt, page_size, entry| {
+        f(virt, entry.addr(), page_size, entry.flags())
+    });
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+/// Like `for_each_mapping`, but hands out the entries that map the pages so
+/// that they can be changed. The caller has to flush the TLB afterwards.
+pub fn for_each_entry(
+    level_4_table: &mut PageTable,
+    mut f: impl FnMut(VirtAddr, MappedPageSize, &mut PageTableEntry),
+) {
+    fn walk(
+        table: &mut PageTable,
+        level: u8,
+        base: u64,
+        f: &mut impl FnMut(VirtAddr, MappedPageSize, &mut PageTableEntry),
+    ) {
+        let offset = physical_memory_offset();
+        for (index, entry) in table.iter_mut().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+
+            let virt = base | ((index as u64) << (12 + 9 * (level as u64 - 1)));
+            let page_size = match level {
+                1 => Some(MappedPageSize::Size4KiB),
+                2 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size2MiB),
+                3 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size1GiB),
+                _ => None,
+            };
+
+            match page_size {
+                Some(page_size) => f(VirtAddr::new_truncate(virt), page_size, entry),
+                None => {
+                    let child = offset + entry.addr().as_u64();
+                    walk(unsafe { &mut *child.as_mut_ptr() }, level - 1, virt, f);
+                }
+            }
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+    walk(level_4_table, 4, 0, &mut f);
+}
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// Start of the ELF header of the kernel, defined by the linker.
+///
+/// The headers are part of the first loaded segment, so the bootloader maps
+/// them together with the kernel.
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// A loaded segment of the kernel ELF file
+#[derive(Debug, Clone, Copy)]
+struct KernelSegment {
+    start: u64,
+    end: u64,
+    flags: u32,
+}
+
+/// Calls `f` for every loaded segment of the kernel
+fn for_each_kernel_segment(mut f: impl FnMut(KernelSegment)) {
+    unsafe {
+        let header = core::ptr::addr_of!(__ehdr_start);
+        let phoff = header.add(0x20).cast::<u64>().read_unaligned();
+        let phentsize = header.add(0x36).cast::<u16>().read_unaligned();
+        let phnum = header.add(0x38).cast::<u16>().read_unaligned();
+
+        for index in 0..usize::from(phnum) {
+            let phdr = header.add(phoff as usize + index * usize::from(phentsize));
+            if phdr.cast::<u32>().read_unaligned() != PT_LOAD {
+                continue;
+            }
+            let flags = phdr.add(0x04).cast::<u32>().read_unaligned();
+            let vaddr = phdr.add(0x10).cast::<u64>().read_unaligned();
+            let memsz = phdr.add(0x28).cast::<u64>().read_unaligned();
+            f(KernelSegment {
+                start: vaddr,
+                end: vaddr + memsz,
+                flags,
+            });
+        }
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// What `enforce_write_xor_execute` found and changed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteXorExecute {
+    /// Pages of the kernel code segments, all read-only and executable
+    pub code_pages: usize,
+    /// Kernel code pages that were mapped no-execute and were made executable
+    pub code_made_executable: usize,
+    /// Pages outside the kernel code that were made no-execute
+    pub data_made_no_execute: usize,
+}
+
+/// Audits the mappings of the given table against the kernel ELF file.
+///
+/// Pages of the kernel code segments must be read-only and are made
+/// executable, every other page is made no-execute, so nothing except the
+/// kernel code can be executed. Panics if a kernel code page is writable,
+/// if it is not mapped, or if it shares a huge page with other memory.
+pub fn enforce_write_xor_execute(mapper: &mut OffsetPageTable) -> WriteXorExecute {
+    let mut code = [(0, 0); 8];
+    let mut code_segments = 0;
+    for_each_kernel_segment(|segment| {
+        if segment.flags & PF_X != 0 {
+            assert!(
+                segment.flags & PF_W == 0,
+                "kernel code segment {:#x}-{:#x} is writable",
+                segment.start,
+                segment.end
+            );
+            assert!(code_segments < code.len(), "too many kernel code segments");
+            code[code_segments] = (segment.start, segment.end);
+            code_segments += 1;
+        }
+    });
+    let code = &code[..code_segments];
+    let code_pages: usize = code
+        .iter()
+        .map(|&(start, end)| {
+            ((align_up(end, Size4KiB::SIZE) - align_down(start, Size4KiB::SIZE)) / Size4KiB::SIZE)
+                as usize
+        })
+        .sum();
+
+    let mut result = WriteXorExecute::default();
+    for_each_entry(mapper.level_4_table(), |virt, page_size, entry| {
+        let start = virt.as_u64();
+        let end = start + page_size.bytes();
+        let flags = entry.flags();
+        if !code
+            .iter()
+            .any(|&(code_start, code_end)| start < code_end && code_start < end)
+        {
+            if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+                result.data_made_no_execute += 1;
+            }
+            return;
+        }
+
+        assert!(
+            page_size == MappedPageSize::Size4KiB,
+            "kernel code at {:?} is mapped by a {} page",
+            virt,
+            page_size
+        );
+        assert!(
+            !flags.contains(PageTableFlags::WRITABLE),
+            "kernel code page at {:?} is writable and executable",
+            virt
+        );
+        if flags.contains(PageTableFlags::NO_EXECUTE) {
+            entry.set_flags(flags - PageTableFlags::NO_EXECUTE);
+            result.code_made_executable += 1;
+        }
+        result.code_pages += 1;
+    });
+
+    assert_eq!(
+        result.code_pages, code_pages,
+        "kernel code is not completely mapped"
+    );
+    x86_64::instructions::tlb::flush_all();
+    result
+}
+
+/// Prints every mapping of the given page table over serial.
+///
+/// Pages that are contiguous in virtual and physical memory and have the
+/// same size and flags are printed as a single range.
+pub fn dump_page_table(mapper: &mut OffsetPageTable) {
+    struct Range {
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: u64,
+        page_size: MappedPageSize,
+        flags: PageTableFlags,
+    }
+
+    fn print(range: &Range) {
+        serial_println!(
+            "  {:#018x}-{:#018x} -> {:#012x} {} {:?}",
+            range.virt.as_u64(),
+            range.virt.as_u64() + range.size,
+            range.phys.as_u64(),
+            range.page_size,
+            range.flags
+        );
+    }
+
+    // accessed and dirty change all the time and would split the ranges
+    let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+    let mut current: Option<Range> = None;
+
+    serial_println!("Page table mappings:");
+    for_each_mapping(mapper.level_4_table(), |virt, phys, page_size, flags| {
+        let flags = flags - ignored;
+        if let Some(range) = &mut current {
+            if range.virt + range.size == virt
+                && range.phys + range.size == phys
+                && range.page_size == page_size
+                && range.flags == flags
+            {
+                range.size += page_size.bytes();
+                return;
+            }
+            print(range);
+        }
+        current = Some(Range {
+            virt,
+            phys,
+            size: page_size.bytes(),
+            page_size,
+            flags,
+        });
+    });
+    if let Some(range) = &current {
+        print(range);
     }
 }
 
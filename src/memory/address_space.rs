@@ -0,0 +1,424 @@
+use super::cow::{self, CowError};
+use super::{KernelMemory, Translation};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{
+    mapper::{MapToError, MapperFlush, UnmapError},
+    page::PageRangeInclusive,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+    PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Level 4 entries from this index on map the kernel and are shared by every
+/// address space, the entries below it belong to the address space.
+///
+/// The kernel is linked into the upper half, and the bootloader is told to
+/// put the physical memory, the boot stack and the boot info there too (see
+/// `Cargo.toml`). This leaves the lower half for user programs.
+pub const FIRST_KERNEL_ENTRY: usize = 256;
+
+/// The heap, kernel stacks, MMIO mappings and other kernel regions mapped
+/// at runtime lie in the 512 GiB starting here, covered by one level 4 entry.
+///
+/// The entry is created before the first address space copies the kernel
+/// entries, so later mappings in it show up in every address space. Kernel
+/// mappings added later under any other upper half entry do not.
+pub const KERNEL_REGIONS_START: u64 = 0xffff_c000_0000_0000;
+
+/// Marks pages whose frames were allocated by the address space and are
+/// freed together with it
+pub const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Set once the level 4 entry of the kernel regions points to a level 3 table
+static KERNEL_REGIONS_PINNED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// The range is not inside the lower half owned by the address space
+    KernelAddress,
+    /// Start or size are not page aligned, or the size is zero
+    InvalidRange,
+    AlreadyMapped,
+    NotMapped,
+    OutOfMemory,
+    /// Too many frames are shared copy-on-write
+    TooManySharedFrames,
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => AddressSpaceError::OutOfMemory,
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => {
+                AddressSpaceError::AlreadyMapped
+            }
+        }
+    }
+}
+
+impl From<CowError> for AddressSpaceError {
+    fn from(err: CowError) -> Self {
+        match err {
+            CowError::HugePage => AddressSpaceError::InvalidRange,
+            CowError::TooManySharedFrames => AddressSpaceError::TooManySharedFrames,
+            CowError::Map(err) => err.into(),
+        }
+    }
+}
+
+/// A set of page tables with its own mappings in the lower half and the
+/// kernel mappings in the upper half.
+///
+/// All page table frames of the lower half are owned by the address space
+/// and are freed when it is dropped, together with the frames mapped by `map`
+/// that are not shared with another address space.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space that only contains the kernel mappings.
+    ///
+    /// Requires `memory::init_kernel_memory` to have been called.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        super::with_kernel_memory(|kernel_memory| {
+            pin_kernel_regions(kernel_memory)?;
+
+            let frame = allocate_table(kernel_memory)?;
+            let table = unsafe { &mut *table_ptr(frame) };
+            let kernel_table = kernel_memory.mapper.level_4_table();
+            for (entry, kernel_entry) in table
+                .iter_mut()
+                .zip(kernel_table.iter())
+                .skip(FIRST_KERNEL_ENTRY)
+            {
+                *entry = kernel_entry.clone();
+            }
+
+            Ok(AddressSpace {
+                level_4_frame: frame,
+            })
+        })
+    }
+
+    /// The frame of the level 4 table, as loaded into `Cr3`
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        unsafe { OffsetPageTable::new(table, super::physical_memory_offset()) }
+    }
+
+    /// Maps `size` bytes starting at `start` to newly allocated, zeroed frames
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let pages = user_pages(start, size)?;
+        let flags = flags | PageTableFlags::PRESENT | OWNED;
+
+        super::with_kernel_memory(|kernel_memory| {
+            for page in pages {
+                let frame = kernel_memory
+                    .frame_allocator
+                    .allocate_frame()
+                    .ok_or(AddressSpaceError::OutOfMemory)?;
+                let virt = super::physical_memory_offset() + frame.start_address().as_u64();
+                unsafe {
+                    core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize)
+                };
+
+                let result = unsafe {
+                    self.mapper()
+                        .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)
+                };
+                match result {
+                    Ok(flush) => self.flush_if_active(flush),
+                    Err(err) => {
+                        unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+                        return Err(err.into());
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Maps `page` to a frame that stays owned by the caller.
+    ///
+    /// Unsafe because the frame must stay valid while it is mapped.
+    pub unsafe fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        user_pages(page.start_address(), Size4KiB::SIZE)?;
+        let flags = (flags | PageTableFlags::PRESENT) - OWNED;
+
+        super::with_kernel_memory(|kernel_memory| {
+            let flush = self
+                .mapper()
+                .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)?;
+            self.flush_if_active(flush);
+            Ok(())
+        })
+    }
+
+    /// Unmaps `size` bytes starting at `start`, freeing frames mapped by `map`
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let pages = user_pages(start, size)?;
+
+        super::with_kernel_memory(|kernel_memory| {
+            for page in pages {
+                let owned = self
+                    .translate(page.start_address())
+                    .ok_or(AddressSpaceError::NotMapped)?
+                    .flags
+                    .contains(OWNED);
+                let (frame, flush) = self.mapper().unmap(page).map_err(|err| match err {
+                    UnmapError::PageNotMapped => AddressSpaceError::NotMapped,
+                    _ => AddressSpaceError::InvalidRange,
+                })?;
+                self.flush_if_active(flush);
+                if cow::release(frame) && owned {
+                    unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Changes the flags of `size` bytes starting at `start`
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        for page in user_pages(start, size)? {
+            let owned = self
+                .translate(page.start_address())
+                .ok_or(AddressSpaceError::NotMapped)?
+                .flags
+                & OWNED;
+            let flush = unsafe {
+                self.mapper()
+                    .update_flags(page, (flags - OWNED) | owned | PageTableFlags::PRESENT)
+            }
+            .map_err(|_| AddressSpaceError::NotMapped)?;
+            self.flush_if_active(flush);
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of the address space that shares all frames.
+    ///
+    /// Writable pages become copy-on-write pages in both address spaces, so
+    /// memory is only copied when one of them writes to it.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut child = AddressSpace::new()?;
+        let level_4_frame = self.level_4_frame;
+
+        super::with_kernel_memory(|kernel_memory| {
+            let mut source = self.mapper();
+            let mut dest = child.mapper();
+            unsafe {
+                for_each_user_page(level_4_frame, |page| {
+                    let frame_allocator = &mut kernel_memory.frame_allocator;
+                    cow::share_page(&mut source, &mut dest, page, frame_allocator)
+                })
+            }
+        })?;
+
+        Ok(child)
+    }
+
+    /// Translates `addr` using the page tables of this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        // the mapper only reads the tables here
+        let table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        let mapper = unsafe { OffsetPageTable::new(table, super::physical_memory_offset()) };
+        super::translate(&mapper, addr)
+    }
+
+    /// Returns true if this address space is currently loaded
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads the address space into `Cr3`.
+    ///
+    /// Unsafe because references into the lower half of the previous
+    /// address space become invalid.
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    fn flush_if_active(&self, flush: MapperFlush<Size4KiB>) {
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "cannot drop the active address space");
+
+        let level_4_frame = self.level_4_frame;
+        super::with_kernel_memory(|kernel_memory| unsafe {
+            free_table(kernel_memory, level_4_frame, 4, FIRST_KERNEL_ENTRY);
+        });
+    }
+}
+
+/// Switches back to the page tables that were active at boot.
+///
+/// Unsafe for the same reason as `AddressSpace::switch`.
+pub unsafe fn switch_to_kernel() {
+    let frame = super::with_kernel_memory(|kernel_memory| {
+        let table = kernel_memory.mapper.level_4_table() as *mut PageTable;
+        let phys = table as u64 - super::physical_memory_offset().as_u64();
+        PhysFrame::containing_address(PhysAddr::new(phys))
+    });
+    Cr3::write(frame, Cr3Flags::empty());
+}
+
+/// Makes sure the level 4 entry of the kernel regions points to a level 3 table.
+///
+/// Address spaces copy the kernel entries when they are created, so without
+/// this a kernel region mapped later would not show up in them.
+fn pin_kernel_regions(kernel_memory: &mut KernelMemory) -> Result<(), AddressSpaceError> {
+    if KERNEL_REGIONS_PINNED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let index = usize::from(VirtAddr::new(KERNEL_REGIONS_START).p4_index());
+    if kernel_memory.mapper.level_4_table()[index].is_unused() {
+        let frame = allocate_table(kernel_memory)?;
+        kernel_memory.mapper.level_4_table()[index]
+            .set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+
+    KERNEL_REGIONS_PINNED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Checks that the range lies in the lower half and returns its pages
+fn user_pages(start: VirtAddr, size: u64) -> Result<PageRangeInclusive, AddressSpaceError> {
+    if size == 0 || !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(AddressSpaceError::InvalidRange);
+    }
+    let last = start
+        .as_u64()
+        .checked_add(size - 1)
+        .and_then(|last| VirtAddr::try_new(last).ok())
+        .ok_or(AddressSpaceError::InvalidRange)?;
+    if usize::from(last.p4_index()) >= FIRST_KERNEL_ENTRY {
+        return Err(AddressSpaceError::KernelAddress);
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(last);
+    Ok(Page::range_inclusive(first, last))
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    (super::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn allocate_table(kernel_memory: &mut KernelMemory) -> Result<PhysFrame, AddressSpaceError> {
+    let frame = kernel_memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(AddressSpaceError::OutOfMemory)?;
+    unsafe { (*table_ptr(frame)).zero() };
+    Ok(frame)
+}
+
+/// Frees the table in `frame` and every table below the first `entries` entries.
+///
+/// Frames mapped by level 1 entries are only freed if they are marked `OWNED`
+/// and no other address space shares them.
+unsafe fn free_table(
+    kernel_memory: &mut KernelMemory,
+    frame: PhysFrame,
+    level: u8,
+    entries: usize,
+) {
+    let table = &*table_ptr(frame);
+    for entry in table.iter().take(entries) {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let child = PhysFrame::containing_address(entry.addr());
+            free_table(kernel_memory, child, level - 1, 512);
+        } else if level == 1 {
+            let child = PhysFrame::containing_address(entry.addr());
+            if cow::release(child) && flags.contains(OWNED) {
+                kernel_memory.frame_allocator.deallocate_frame(child);
+            }
+        }
+    }
+    kernel_memory.frame_allocator.deallocate_frame(frame);
+}
+
+/// Calls `f` for every 4 KiB page mapped in the lower half.
+///
+/// Entries are read through raw pointers, so `f` may change the mappings
+/// of the page it is called for.
+unsafe fn for_each_user_page<E>(
+    level_4_frame: PhysFrame,
+    mut f: impl FnMut(Page) -> Result<(), E>,
+) -> Result<(), E> {
+    let present = |table: *const PageTable, index: usize| -> Option<PhysFrame> {
+        let flags = (*table)[index].flags();
+        if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            Some(PhysFrame::containing_address((*table)[index].addr()))
+        } else {
+            None
+        }
+    };
+
+    let p4 = table_ptr(level_4_frame);
+    for i4 in 0..FIRST_KERNEL_ENTRY {
+        let p3 = match present(p4, i4) {
+            Some(frame) => table_ptr(frame),
+            None => continue,
+        };
+        for i3 in 0..512 {
+            let p2 = match present(p3, i3) {
+                Some(frame) => table_ptr(frame),
+                None => continue,
+            };
+            for i2 in 0..512 {
+                let p1 = match present(p2, i2) {
+                    Some(frame) => table_ptr(frame),
+                    None => continue,
+                };
+                for i1 in 0..512 {
+                    if (*p1)[i1].flags().contains(PageTableFlags::PRESENT) {
+                        f(Page::from_page_table_indices(
+                            PageTableIndex::new(i4 as u16),
+                            PageTableIndex::new(i3 as u16),
+                            PageTableIndex::new(i2 as u16),
+                            PageTableIndex::new(i1 as u16),
+                        ))?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// A physical frame allocator that tracks every frame with a single bit.
+///
+/// The bitmap is built once from the bootloader memory map and is stored
+/// in the first usable region that is large enough to hold it. A set bit
+/// means the frame is in use (or not usable at all), a cleared bit means
+/// the frame is free.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    memory_map: &'static MemoryMap,
+    /// Number of usable frames reported by the memory map
+    usable_frames: usize,
+    /// Number of usable frames currently handed out (including the bitmap itself)
+    used_frames: usize,
+    /// Word index to start the next search from
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a frame allocator from the passed memory map.
+    ///
+    /// Unsafe because the caller must guarantee that the memory map is valid,
+    /// that all frames marked as `USABLE` are really unused, and that the
+    /// complete physical memory is mapped at `physical_mem_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_mem_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // the bitmap needs to cover every frame up to the end of the last usable region
+        let memory_end = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .expect("no usable memory regions");
+        let frame_count = (memory_end / FRAME_SIZE) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (words * 8) as u64;
+
+        // place the bitmap at the start of the first region that can hold it
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes)
+            .expect("no usable memory region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr: *mut u64 = (physical_mem_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+
+        // everything is used until the memory map says otherwise
+        bitmap.fill(!0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            memory_map,
+            usable_frames: 0,
+            used_frames: 0,
+            next: 0,
+        };
+
+        for region in usable_regions() {
+            let start = region.range.start_addr() / FRAME_SIZE;
+            let end = region.range.end_addr() / FRAME_SIZE;
+            for frame in start..end {
+                allocator.clear(frame as usize);
+                allocator.usable_frames += 1;
+            }
+        }
+
+        // the frames holding the bitmap are not available to anyone else
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+        let first = bitmap_start / FRAME_SIZE;
+        for frame in first..first + bitmap_frames {
+            allocator.set(frame as usize);
+            allocator.used_frames += 1;
+        }
+
+        allocator
+    }
+
+    /// Number of usable frames managed by this allocator
+    pub fn total_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Number of usable frames that are currently allocated
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    /// Number of frames that can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.usable_frames - self.used_frames
+    }
+
+    /// Returns whether the given frame is currently marked as used.
+    pub fn is_used(&self, frame: PhysFrame) -> bool {
+        let index = Self::frame_index(frame);
+        match self.bitmap.get(index / BITS_PER_WORD) {
+            Some(word) => word & (1 << (index % BITS_PER_WORD)) != 0,
+            // frames outside the bitmap are never usable
+            None => true,
+        }
+    }
+
+    /// Returns whether the memory map lists the given frame as usable.
+    ///
+    /// Frames that are not usable read as used, but were never allocated.
+    pub fn is_usable(&self, frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        self.memory_map.iter().any(|region| {
+            region.region_type == MemoryRegionType::Usable
+                && region.range.start_addr() <= addr
+                && addr < region.range.end_addr()
+        })
+    }
+
+    fn frame_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    /// Finds the first word at or after `next` that still has a free bit,
+    /// wrapping around to the start of the bitmap once.
+    fn find_free_word(&self) -> Option<usize> {
+        let words = self.bitmap.len();
+        (self.next..words)
+            .chain(0..self.next)
+            .find(|&i| self.bitmap[i] != !0)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let word = self.find_free_word()?;
+        // the lowest cleared bit is the first free frame in this word
+        let bit = self.bitmap[word].trailing_ones() as usize;
+        let index = word * BITS_PER_WORD + bit;
+
+        self.set(index);
+        self.used_frames += 1;
+        self.next = word;
+
+        let addr = PhysAddr::new(index as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        assert!(self.is_usable(frame), "free of unusable frame {:?}", frame);
+        assert!(self.is_used(frame), "double free of frame {:?}", frame);
+
+        let index = Self::frame_index(frame);
+        self.clear(index);
+        self.used_frames -= 1;
+
+        // freed frames are found again quickly on the next allocation
+        self.next = self.next.min(index / BITS_PER_WORD);
+    }
+}
@@ -0,0 +1,284 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Largest supported order, a block of order `n` spans `2^n` frames.
+///
+/// Order 18 is a 1 GiB block which is the largest page size on x86_64.
+pub const MAX_ORDER: usize = 18;
+
+/// Order of a block that backs a 2 MiB huge page
+pub const ORDER_2MIB: usize = 9;
+/// Order of a block that backs a 1 GiB huge page
+pub const ORDER_1GIB: usize = 18;
+
+/// Sentinel stored in the `next` field of the last block of a free list
+const END_OF_LIST: u64 = u64::MAX;
+
+/// Header written to the start of every free block
+struct FreeBlock {
+    next: u64,
+}
+
+/// A buddy system allocator for physically contiguous runs of frames.
+///
+/// Free blocks of each order are kept in singly linked lists whose nodes
+/// live inside the free blocks themselves, so no memory besides the
+/// allocator struct is needed. Freeing a block merges it with its buddy
+/// as long as the buddy is free as well.
+///
+/// The allocator takes ownership of all usable memory in the memory map,
+/// so it must not be used together with another frame allocator that was
+/// created from the same map. The kernel uses it as the frame allocator of
+/// `KernelMemory`, which makes contiguous and huge frames available to all
+/// kernel code.
+pub struct BuddyFrameAllocator {
+    free_lists: [u64; MAX_ORDER + 1],
+    physical_mem_offset: VirtAddr,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+/// Returns the smallest order whose blocks can hold `frames` frames.
+pub fn order_for(frames: usize) -> Option<usize> {
+    let order = frames.max(1).next_power_of_two().trailing_zeros() as usize;
+    if order <= MAX_ORDER {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+impl BuddyFrameAllocator {
+    /// Create a buddy allocator that owns all usable regions of the memory map.
+    ///
+    /// Unsafe because the caller must guarantee that the memory map is valid,
+    /// that all frames marked as `USABLE` are really unused, and that the
+    /// complete physical memory is mapped at `physical_mem_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_mem_offset: VirtAddr) -> Self {
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [END_OF_LIST; MAX_ORDER + 1],
+            physical_mem_offset,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        for region in usable_regions {
+            let mut start = region.range.start_addr() / FRAME_SIZE;
+            let end = region.range.end_addr() / FRAME_SIZE;
+
+            // split the region into the largest naturally aligned blocks that fit
+            while start < end {
+                let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+                while start + (1 << order) > end {
+                    order -= 1;
+                }
+
+                allocator.push(PhysAddr::new(start * FRAME_SIZE), order);
+                allocator.total_frames += 1 << order;
+                allocator.free_frames += 1 << order;
+                start += 1 << order;
+            }
+        }
+
+        allocator
+    }
+
+    /// Number of usable frames managed by this allocator
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of frames that are currently allocated
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Number of frames that can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free blocks of the given order
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.free_lists[order];
+        while current != END_OF_LIST {
+            count += 1;
+            current = unsafe { (*self.block(current)).next };
+        }
+        count
+    }
+
+    /// Allocates `2^order` physically contiguous frames.
+    ///
+    /// The returned address is aligned to the size of the block.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+
+        // find the smallest block that is large enough
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != END_OF_LIST)?;
+        let block = self.pop(current)?;
+
+        // split the block, putting the upper halves back on the free lists
+        while current > order {
+            current -= 1;
+            let buddy = block + ((1u64 << current) * FRAME_SIZE);
+            self.push(buddy, current);
+        }
+
+        self.free_frames -= 1 << order;
+        Some(block)
+    }
+
+    /// Frees a block previously returned by `allocate` with the same order.
+    ///
+    /// Panics if any part of the block is already free.
+    ///
+    /// Unsafe because the caller must guarantee that the block is no longer in use.
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        assert!(
+            addr.is_aligned((1u64 << order) * FRAME_SIZE),
+            "block {:?} is not aligned to order {}",
+            addr,
+            order
+        );
+        assert!(
+            !self.overlaps_free_block(addr, order),
+            "double free of block {:?} with order {}",
+            addr,
+            order
+        );
+
+        self.free_frames += 1 << order;
+
+        // merge with the buddy for as long as it is free
+        let mut block = addr;
+        let mut current = order;
+        while current < MAX_ORDER {
+            let buddy = PhysAddr::new(block.as_u64() ^ ((1u64 << current) * FRAME_SIZE));
+            if !self.remove(buddy, current) {
+                break;
+            }
+            block = block.min(buddy);
+            current += 1;
+        }
+
+        self.push(block, current);
+    }
+
+    /// Returns true if the block overlaps a block on any of the free lists.
+    ///
+    /// Looks at every order, as the block may be part of a larger free block
+    /// or contain smaller ones after a partial free.
+    fn overlaps_free_block(&self, addr: PhysAddr, order: usize) -> bool {
+        let start = addr.as_u64();
+        let end = start + (1u64 << order) * FRAME_SIZE;
+
+        (0..=MAX_ORDER).any(|free_order| {
+            let size = (1u64 << free_order) * FRAME_SIZE;
+            let mut current = self.free_lists[free_order];
+            while current != END_OF_LIST {
+                if current < end && start < current + size {
+                    return true;
+                }
+                current = unsafe { (*self.block(current)).next };
+            }
+            false
+        })
+    }
+
+    /// Pointer to the header of the free block at the given physical address
+    fn block(&self, addr: u64) -> *mut FreeBlock {
+        let virt = self.physical_mem_offset + addr;
+        virt.as_mut_ptr()
+    }
+
+    fn push(&mut self, addr: PhysAddr, order: usize) {
+        let next = self.free_lists[order];
+        unsafe { (*self.block(addr.as_u64())).next = next };
+        self.free_lists[order] = addr.as_u64();
+    }
+
+    fn pop(&mut self, order: usize) -> Option<PhysAddr> {
+        let head = self.free_lists[order];
+        if head == END_OF_LIST {
+            return None;
+        }
+        self.free_lists[order] = unsafe { (*self.block(head)).next };
+        Some(PhysAddr::new(head))
+    }
+
+    /// Removes the given block from the free list of `order`.
+    ///
+    /// Returns false if the block was not free.
+    fn remove(&mut self, addr: PhysAddr, order: usize) -> bool {
+        let target = addr.as_u64();
+
+        if self.free_lists[order] == target {
+            self.free_lists[order] = unsafe { (*self.block(target)).next };
+            return true;
+        }
+
+        let mut current = self.free_lists[order];
+        while current != END_OF_LIST {
+            let node = self.block(current);
+            unsafe {
+                if (*node).next == target {
+                    (*node).next = (*self.block(target)).next;
+                    return true;
+                }
+                current = (*node).next;
+            }
+        }
+
+        false
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0).map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(ORDER_2MIB).map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate(ORDER_1GIB).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame.start_address(), 0)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(frame.start_address(), ORDER_2MIB)
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate(frame.start_address(), ORDER_1GIB)
+    }
+}
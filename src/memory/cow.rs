@@ -0,0 +1,270 @@
+use super::vma::FaultError;
+use super::{KernelMemory, MappedPageSize};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Marks pages that are shared read-only and get copied on the first write
+pub const COW: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Number of slots in the reference count table, one less frame can be
+/// shared at once
+const MAX_SHARED_FRAMES: usize = 4096;
+
+static REF_COUNTS: Mutex<RefCounts> = Mutex::new(RefCounts::new());
+
+/// Number of mappings for every shared frame.
+///
+/// Frames that are mapped only once are not stored, so the table only has
+/// to hold frames that are actually shared. Uses linear probing so that
+/// the page fault handler does not need to allocate. At least one slot is
+/// always empty, which ends every probe for a frame that is not shared.
+struct RefCounts {
+    /// Frame number plus one, zero marks an empty slot
+    frames: [u64; MAX_SHARED_FRAMES],
+    counts: [usize; MAX_SHARED_FRAMES],
+    len: usize,
+}
+
+impl RefCounts {
+    const fn new() -> Self {
+        RefCounts {
+            frames: [0; MAX_SHARED_FRAMES],
+            counts: [0; MAX_SHARED_FRAMES],
+            len: 0,
+        }
+    }
+
+    fn key(frame: PhysFrame) -> u64 {
+        frame.start_address().as_u64() / Size4KiB::SIZE + 1
+    }
+
+    fn slot(key: u64) -> usize {
+        (key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % MAX_SHARED_FRAMES
+    }
+
+    fn find(&self, key: u64) -> Option<usize> {
+        let mut index = Self::slot(key);
+        loop {
+            match self.frames[index] {
+                0 => return None,
+                k if k == key => return Some(index),
+                _ => index = (index + 1) % MAX_SHARED_FRAMES,
+            }
+        }
+    }
+
+    fn get(&self, frame: PhysFrame) -> usize {
+        self.find(Self::key(frame)).map_or(1, |index| self.counts[index])
+    }
+
+    fn increment(&mut self, frame: PhysFrame) -> bool {
+        let key = Self::key(frame);
+        if let Some(index) = self.find(key) {
+            self.counts[index] += 1;
+            return true;
+        }
+        if self.len == MAX_SHARED_FRAMES - 1 {
+            return false;
+        }
+
+        let mut index = Self::slot(key);
+        while self.frames[index] != 0 {
+            index = (index + 1) % MAX_SHARED_FRAMES;
+        }
+        self.frames[index] = key;
+        self.counts[index] = 2;
+        self.len += 1;
+        true
+    }
+
+    /// Returns true if the last mapping of the frame was released
+    fn decrement(&mut self, frame: PhysFrame) -> bool {
+        let index = match self.find(Self::key(frame)) {
+            Some(index) => index,
+            None => return true,
+        };
+
+        self.counts[index] -= 1;
+        if self.counts[index] == 1 {
+            self.remove(index);
+        }
+        false
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.frames[index] = 0;
+        self.len -= 1;
+
+        // move following entries back so that lookups don't stop at the hole
+        let mut hole = index;
+        let mut next = (index + 1) % MAX_SHARED_FRAMES;
+        while self.frames[next] != 0 {
+            let home = Self::slot(self.frames[next]);
+            let distance_to_home = (next + MAX_SHARED_FRAMES - home) % MAX_SHARED_FRAMES;
+            let distance_to_hole = (next + MAX_SHARED_FRAMES - hole) % MAX_SHARED_FRAMES;
+            if distance_to_home >= distance_to_hole {
+                self.frames[hole] = self.frames[next];
+                self.counts[hole] = self.counts[next];
+                self.frames[next] = 0;
+                hole = next;
+            }
+            next = (next + 1) % MAX_SHARED_FRAMES;
+        }
+    }
+}
+
+/// Returns the number of mappings of `frame`, one if it is not shared
+pub fn ref_count(frame: PhysFrame) -> usize {
+    without_interrupts(|| REF_COUNTS.lock().get(frame))
+}
+
+/// Drops one mapping of `frame`.
+///
+/// Returns true if it was the last one and the frame can be freed.
+pub fn release(frame: PhysFrame) -> bool {
+    without_interrupts(|| REF_COUNTS.lock().decrement(frame))
+}
+
+#[derive(Debug)]
+pub enum CowError {
+    /// Huge pages cannot be shared
+    HugePage,
+    /// The reference count table is full
+    TooManySharedFrames,
+    Map(MapToError<Size4KiB>),
+}
+
+/// Maps `page` of `dest` to the frame `page` is mapped to in `source`.
+///
+/// Writable pages become read-only copy-on-write pages in both tables.
+/// Pages not mapped in `source` are skipped.
+///
+/// Unsafe because `dest` must not map `page` yet and the caller must not
+/// hold references to the memory of the page that it writes through.
+pub unsafe fn share_page(
+    source: &mut OffsetPageTable,
+    dest: &mut OffsetPageTable,
+    page: Page,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), CowError> {
+    let translation = match super::translate(source, page.start_address()) {
+        Some(translation) => translation,
+        None => return Ok(()),
+    };
+    if translation.page_size != MappedPageSize::Size4KiB {
+        return Err(CowError::HugePage);
+    }
+
+    let frame = PhysFrame::containing_address(translation.phys_addr);
+    if !without_interrupts(|| REF_COUNTS.lock().increment(frame)) {
+        return Err(CowError::TooManySharedFrames);
+    }
+
+    let mut flags = translation.flags;
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags = (flags - PageTableFlags::WRITABLE) | COW;
+        source
+            .update_flags(page, flags)
+            .expect("page was translated")
+            .flush();
+    }
+
+    match dest.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            release(frame);
+            Err(CowError::Map(err))
+        }
+    }
+}
+
+/// Shares `size` bytes starting at `start` from `source` with `dest`, see `share_page`
+pub unsafe fn clone_range(
+    source: &mut OffsetPageTable,
+    dest: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), CowError> {
+    if size == 0 {
+        return Ok(());
+    }
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(first, last) {
+        share_page(source, dest, page, frame_allocator)?;
+    }
+    Ok(())
+}
+
+/// Resolves a write fault on a copy-on-write page of the active page table.
+///
+/// Called by the page fault handler, execution can resume if this returns `Ok`.
+pub fn handle_write_fault(addr: VirtAddr) -> Result<(), FaultError> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let offset = super::physical_memory_offset();
+    // the active table is used so that this works for every address space
+    let mut mapper = unsafe { OffsetPageTable::new(super::active_level4_table(offset), offset) };
+
+    let translation =
+        super::translate(&mapper, page.start_address()).ok_or(FaultError::ProtectionViolation)?;
+    if !translation.flags.contains(COW) || translation.page_size != MappedPageSize::Size4KiB {
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    let frame = PhysFrame::containing_address(translation.phys_addr);
+    let flags = (translation.flags - COW) | PageTableFlags::WRITABLE;
+
+    // the other mappings are gone already, the page can be written in place
+    if ref_count(frame) == 1 {
+        unsafe { mapper.update_flags(page, flags) }
+            .expect("page was translated")
+            .flush();
+        return Ok(());
+    }
+
+    super::try_with_kernel_memory(|kernel_memory| unsafe {
+        copy_page(kernel_memory, &mut mapper, page, frame, flags)
+    })
+    .ok_or(FaultError::KernelMemoryUnavailable)?
+}
+
+/// Maps `page` to a copy of `frame`
+unsafe fn copy_page(
+    kernel_memory: &mut KernelMemory,
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), FaultError> {
+    let offset = super::physical_memory_offset();
+    let copy = kernel_memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(FaultError::OutOfMemory)?;
+    core::ptr::copy_nonoverlapping(
+        (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+        (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+        Size4KiB::SIZE as usize,
+    );
+
+    let (_, flush) = mapper.unmap(page).expect("page was translated");
+    flush.ignore();
+    // the copy is always freed together with the mapping
+    let flags = flags | super::address_space::OWNED;
+    mapper
+        .map_to(page, copy, flags, &mut kernel_memory.frame_allocator)
+        .expect("page was just unmapped")
+        .flush();
+
+    release(frame);
+    Ok(())
+}
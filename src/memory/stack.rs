@@ -0,0 +1,136 @@
+use super::vma::{self, VmaError};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::{align_up, VirtAddr};
+
+/// Start of the virtual region that holds all kernel stacks, in the kernel
+/// regions
+pub const STACKS_START: u64 = 0x_ffff_c066_0000_0000;
+/// Every stack gets a slot of this size, the stack sits at the top of its
+/// slot and everything below it is left unmapped as guard area
+const SLOT_SIZE: u64 = 1024 * 1024; // 1 MiB
+/// Largest stack that can be allocated, keeps at least one guard page below it
+pub const MAX_STACK_SIZE: u64 = SLOT_SIZE - Size4KiB::SIZE;
+const MAX_STACKS: usize = 256;
+
+/// Size of the stack in every slot, `None` if the slot is free
+static STACKS: Mutex<[Option<u64>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// A kernel stack with an unmapped guard area below it.
+///
+/// All pages are mapped when the stack is allocated. Growing into unmapped
+/// pages would fault while the code on the stack may hold the kernel memory
+/// lock, and the fault handler needs that lock to map them.
+#[derive(Debug)]
+pub struct KernelStack {
+    id: usize,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Number used for the stack in overflow reports
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Initial stack pointer, stacks grow downward
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Lowest usable address of the stack
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// The size is zero or larger than `MAX_STACK_SIZE`
+    InvalidSize,
+    /// `MAX_STACKS` stacks are already allocated
+    TooManyStacks,
+    /// The stack region could not be registered
+    Vma(VmaError),
+    OutOfMemory,
+}
+
+fn slot_start(id: usize) -> VirtAddr {
+    VirtAddr::new(STACKS_START + id as u64 * SLOT_SIZE)
+}
+
+/// Allocates a kernel stack of `size` bytes, rounded up to whole pages.
+///
+/// Requires `memory::init_kernel_memory` to have been called.
+pub fn allocate(size: u64) -> Result<KernelStack, StackError> {
+    let size = align_up(size, Size4KiB::SIZE);
+    if size == 0 || size > MAX_STACK_SIZE {
+        return Err(StackError::InvalidSize);
+    }
+
+    let id = without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let id = stacks.iter().position(|slot| slot.is_none())?;
+        stacks[id] = Some(size);
+        Some(id)
+    })
+    .ok_or(StackError::TooManyStacks)?;
+
+    let top = slot_start(id) + SLOT_SIZE;
+    let stack = KernelStack {
+        id,
+        bottom: top - size,
+        top,
+    };
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(err) = vma::register("kernel stack", stack.bottom, size, flags) {
+        release_slot(id);
+        return Err(StackError::Vma(err));
+    }
+
+    if vma::populate(stack.bottom, size).is_err() {
+        vma::unregister(stack.bottom);
+        release_slot(id);
+        return Err(StackError::OutOfMemory);
+    }
+
+    Ok(stack)
+}
+
+/// Unmaps the stack and gives its frames back to the frame allocator.
+///
+/// Unsafe because nothing may run on the stack anymore.
+pub unsafe fn free(stack: KernelStack) {
+    vma::unregister(stack.bottom);
+    release_slot(stack.id);
+}
+
+fn release_slot(id: usize) {
+    without_interrupts(|| STACKS.lock()[id] = None);
+}
+
+/// Returns the id of the stack whose guard area contains `addr`.
+///
+/// Used by the page fault handler to tell stack overflows apart from other faults.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<usize> {
+    let offset = addr.as_u64().checked_sub(STACKS_START)?;
+    let id = (offset / SLOT_SIZE) as usize;
+    if id >= MAX_STACKS {
+        return None;
+    }
+
+    let size = without_interrupts(|| STACKS.lock()[id])?;
+    let bottom = slot_start(id) + (SLOT_SIZE - size);
+    if addr < bottom {
+        Some(id)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,169 @@
+use super::address_space::FIRST_KERNEL_ENTRY;
+use super::vma::FaultError;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::structures::paging::{OffsetPageTable, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Alignment check flag in RFLAGS, allows supervisor access to user pages while SMAP is on
+const RFLAGS_AC: u64 = 1 << 18;
+
+static SMEP_ENABLED: AtomicBool = AtomicBool::new(false);
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on SMEP and SMAP if the CPU supports them.
+///
+/// With SMEP the kernel faults when executing user pages, with SMAP it faults
+/// when touching user pages outside of `copy_from_user` and `copy_to_user`.
+pub fn init() {
+    // structured extended feature flags, older CPUs do not have the leaf and
+    // return the data of their highest leaf instead
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let features = if max_leaf >= 7 {
+        unsafe { __cpuid_count(7, 0) }.ebx
+    } else {
+        0
+    };
+    let smep = features & (1 << 7) != 0;
+    let smap = features & (1 << 20) != 0;
+
+    unsafe {
+        Cr4::update(|flags| {
+            if smep {
+                *flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+            }
+            if smap {
+                *flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+            }
+        });
+    }
+
+    SMEP_ENABLED.store(smep, Ordering::Relaxed);
+    SMAP_ENABLED.store(smap, Ordering::Relaxed);
+}
+
+pub fn smep_enabled() -> bool {
+    SMEP_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    /// The range is not inside the user half of the address space
+    NotUserAddress,
+    /// A page of the range is not mapped as user accessible
+    NotMapped,
+    /// A page of the range is not writable
+    NotWritable,
+}
+
+/// Allows the kernel to access user pages while it exists
+struct UserAccess;
+
+impl UserAccess {
+    fn begin() -> Self {
+        if smap_enabled() {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccess
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if smap_enabled() {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+/// Checks that `len` bytes starting at `addr` are user pages of the active
+/// address space that can be read, and written if `write` is set
+fn check_range(addr: VirtAddr, len: usize, write: bool) -> Result<(), UserAccessError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let last = addr
+        .as_u64()
+        .checked_add(len as u64 - 1)
+        .and_then(|last| VirtAddr::try_new(last).ok())
+        .ok_or(UserAccessError::NotUserAddress)?;
+    if usize::from(last.p4_index()) >= FIRST_KERNEL_ENTRY {
+        return Err(UserAccessError::NotUserAddress);
+    }
+
+    let offset = super::physical_memory_offset();
+    let mapper = unsafe { OffsetPageTable::new(super::active_level4_table(offset), offset) };
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let last = Page::<Size4KiB>::containing_address(last);
+    for page in Page::range_inclusive(first, last) {
+        let flags = super::translate(&mapper, page.start_address())
+            .ok_or(UserAccessError::NotMapped)?
+            .flags;
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err(UserAccessError::NotMapped);
+        }
+        // copy-on-write pages are copied by the page fault handler
+        if write && !flags.intersects(PageTableFlags::WRITABLE | super::cow::COW) {
+            return Err(UserAccessError::NotWritable);
+        }
+    }
+    Ok(())
+}
+
+/// Copies `dest.len()` bytes from user memory at `src` into `dest`
+pub fn copy_from_user(dest: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    check_range(src, dest.len(), false)?;
+
+    let _access = UserAccess::begin();
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dest.as_mut_ptr(), dest.len()) };
+    Ok(())
+}
+
+/// Copies `src` into user memory at `dest`
+pub fn copy_to_user(dest: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    check_range(dest, src.len(), true)?;
+
+    let _access = UserAccess::begin();
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dest.as_mut_ptr::<u8>(), src.len()) };
+    Ok(())
+}
+
+/// Returns the violation if a page fault was caused by the kernel executing
+/// or touching a user page while SMEP or SMAP forbid it
+pub fn check_supervisor_access(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    stack_frame: &InterruptStackFrameValue,
+) -> Result<(), FaultError> {
+    if !(smep_enabled() || smap_enabled())
+        || error_code.contains(PageFaultErrorCode::USER_MODE)
+        || !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        return Ok(());
+    }
+
+    let offset = super::physical_memory_offset();
+    let mapper = unsafe { OffsetPageTable::new(super::active_level4_table(offset), offset) };
+    let user_page = super::translate(&mapper, addr).map_or(false, |translation| {
+        translation.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    });
+    if !user_page {
+        return Ok(());
+    }
+
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        if smep_enabled() {
+            return Err(FaultError::SmepViolation);
+        }
+    } else if smap_enabled() && stack_frame.cpu_flags & RFLAGS_AC == 0 {
+        return Err(FaultError::SmapViolation);
+    }
+    Ok(())
+}
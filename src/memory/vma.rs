@@ -0,0 +1,256 @@
+use super::address_space::FIRST_KERNEL_ENTRY;
+use super::KernelMemory;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+    PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Maximum number of regions that can be registered at once
+const MAX_VMAS: usize = 64;
+
+static VMAS: Mutex<[Option<Vma>; MAX_VMAS]> = Mutex::new([None; MAX_VMAS]);
+
+/// A region of virtual memory that is backed by frames on first access.
+///
+/// Registering a region does not map anything. Pages are mapped by the page
+/// fault handler when they are touched for the first time, so large sparse
+/// regions only use memory for the pages that are actually used.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// First address after the region
+    pub end: VirtAddr,
+    /// Flags for the pages mapped in the region, `PRESENT` is added automatically
+    pub flags: PageTableFlags,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::<Size4KiB>::containing_address(self.start);
+        let end = Page::<Size4KiB>::containing_address(self.end);
+        Page::range(start, end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Start or size are not page aligned, the size is zero, or the range
+    /// is not in the upper half
+    InvalidRange,
+    /// The region overlaps an already registered one
+    Overlap,
+    /// `MAX_VMAS` regions are already registered
+    TooManyRegions,
+}
+
+/// Registers a lazily backed region of `size` bytes starting at `start`.
+///
+/// The range must not be mapped by anything else. Faults are resolved in
+/// the kernel page table, so it has to lie in the upper half that every
+/// address space shares.
+pub fn register(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Vma, VmaError> {
+    if size == 0 || !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(VmaError::InvalidRange);
+    }
+    if usize::from(start.p4_index()) < FIRST_KERNEL_ENTRY {
+        return Err(VmaError::InvalidRange);
+    }
+    let end = start
+        .as_u64()
+        .checked_add(size)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(VmaError::InvalidRange)?;
+
+    let vma = Vma {
+        name,
+        start,
+        end,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+
+    without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        if vmas.iter().flatten().any(|other| other.overlaps(&vma)) {
+            return Err(VmaError::Overlap);
+        }
+        let slot = vmas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::TooManyRegions)?;
+        *slot = Some(vma);
+        Ok(vma)
+    })
+}
+
+/// Removes the region starting at `start`, unmapping its pages and giving
+/// their frames back to the frame allocator.
+///
+/// Page table frames are kept, they are likely to be reused by the next
+/// region in the same part of the address space.
+pub fn unregister(start: VirtAddr) -> Option<Vma> {
+    let vma = without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        let slot = vmas
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(vma) if vma.start == start))?;
+        slot.take()
+    })?;
+
+    super::with_kernel_memory(|kernel_memory| {
+        for page in vma.pages() {
+            if let Ok((frame, flush)) = kernel_memory.mapper.unmap(page) {
+                flush.flush();
+                unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    });
+
+    Some(vma)
+}
+
+/// Maps the pages of `size` bytes starting at `start` right away instead of
+/// waiting for them to be touched.
+///
+/// The range must lie inside a registered region, pages that are already
+/// mapped are left alone.
+pub fn populate(start: VirtAddr, size: u64) -> Result<(), FaultError> {
+    let vma = find(start).ok_or(FaultError::NoRegion)?;
+    let end = start + size;
+    if end > vma.end {
+        return Err(FaultError::NoRegion);
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(end - 1u64);
+    let pages = Page::range_inclusive(first, last);
+    super::with_kernel_memory(|kernel_memory| {
+        pages
+            .filter(|page| kernel_memory.mapper.translate_page(*page).is_err())
+            .try_for_each(|page| map_zeroed(kernel_memory, page, vma.flags))
+    })
+}
+
+/// Returns the region containing `addr`
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    without_interrupts(|| {
+        let vmas = VMAS.lock();
+        vmas.iter().flatten().find(|vma| vma.contains(addr)).copied()
+    })
+}
+
+/// Why a page fault could not be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not part of any registered region
+    NoRegion,
+    /// The page is mapped, but the access is not allowed
+    ProtectionViolation,
+    /// Write to a region that is not writable
+    NotWritable,
+    /// The kernel memory is locked by the code that faulted
+    KernelMemoryUnavailable,
+    OutOfMemory,
+    /// The region lies inside a huge page mapped by someone else
+    HugePageConflict,
+    /// The kernel executed code in a user page
+    SmepViolation,
+    /// The kernel touched a user page outside of the user access helpers
+    SmapViolation,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            FaultError::NoRegion => "address outside of any memory region",
+            FaultError::ProtectionViolation => "protection violation",
+            FaultError::NotWritable => "write to read-only memory region",
+            FaultError::KernelMemoryUnavailable => "kernel memory locked during fault",
+            FaultError::OutOfMemory => "out of memory",
+            FaultError::HugePageConflict => "memory region inside a huge page",
+            FaultError::SmepViolation => "SMEP violation, kernel executed a user page",
+            FaultError::SmapViolation => "SMAP violation, kernel accessed a user page",
+        };
+        f.write_str(message)
+    }
+}
+
+/// Resolves a page fault at `addr` by mapping a zeroed frame if the address
+/// belongs to a registered region.
+///
+/// Called by the page fault handler, execution can resume if this returns `Ok`.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    // lazily backed pages are never present, so this fault is not ours
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    let vma = find(addr).ok_or(FaultError::NoRegion)?;
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !vma.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return Err(FaultError::NotWritable);
+    }
+
+    let page = Page::containing_address(addr);
+    super::try_with_kernel_memory(|kernel_memory| map_zeroed(kernel_memory, page, vma.flags))
+        .ok_or(FaultError::KernelMemoryUnavailable)?
+}
+
+fn map_zeroed(
+    kernel_memory: &mut KernelMemory,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), FaultError> {
+    let frame = kernel_memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(FaultError::OutOfMemory)?;
+
+    // zero the frame through the physical memory mapping before it becomes visible
+    let virt = super::physical_memory_offset() + frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize) };
+
+    let result = unsafe {
+        kernel_memory
+            .mapper
+            .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+            match err {
+                // the fault raced with another mapping of the same page
+                MapToError::PageAlreadyMapped(_) => Ok(()),
+                MapToError::FrameAllocationFailed => Err(FaultError::OutOfMemory),
+                MapToError::ParentEntryHugePage => Err(FaultError::HugePageConflict),
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::interrupts::irq::{self, IrqError, IrqHandler};
+use crate::time;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_PERIODIC_INTERRUPT: u8 = 1 << 6;
+/// Set in 12 hour mode for hours after noon
+const HOUR_PM: u8 = 1 << 7;
+
+/// ISA IRQ of the real-time clock
+pub const IRQ: u8 = 8;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Rate the periodic interrupt was enabled with, zero if it is off
+static PERIODIC_RATE: AtomicU8 = AtomicU8::new(0);
+/// Set while `handle_interrupt` is registered for `IRQ`
+static IRQ_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Cmos {
+            address: Port::new(0x70),
+            data: Port::new(0x71),
+        }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address.write(register);
+            self.data.write(value);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Reads the raw date and time registers once no update is running
+    fn read_raw(&mut self) -> [u8; 6] {
+        while self.update_in_progress() {
+            core::hint::spin_loop();
+        }
+        [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| self.read(register))
+    }
+}
+
+/// A calendar date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts seconds since the Unix epoch to a date
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = timestamp / SECONDS_PER_DAY;
+        let seconds = timestamp % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * SECONDS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01 of a date in the Gregorian calendar.
+///
+/// Counts in eras of 400 years starting in March, so that the leap day is
+/// the last day of the year.
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = u64::from(year) - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month = u64::from(month);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + u64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // 719468 days lie between 0000-03-01 and 1970-01-01
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year as u16, month as u8, day as u8)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the current date and time from the CMOS clock.
+///
+/// The clock is expected to run in UTC, and years are taken to be in the 21st century.
+pub fn read() -> DateTime {
+    let (raw, status_b) = without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        // read until two reads agree, an update may have started in between
+        let mut raw = cmos.read_raw();
+        loop {
+            let again = cmos.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(STATUS_B))
+    });
+
+    let [second, minute, hour, day, month, year] = raw;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    // the PM flag is not part of the BCD value
+    let mut hour_value = decode(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour_value %= 12;
+        if hour & HOUR_PM != 0 {
+            hour_value += 12;
+        }
+    }
+
+    DateTime {
+        year: 2000 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour: hour_value,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Sets the wall clock of `time::now` from the CMOS clock.
+///
+/// The CMOS clock only has a resolution of one second, so the wall clock
+/// may be behind by up to a second. After this it advances with the
+/// monotonic uptime clock.
+pub fn init() {
+    let timestamp = Duration::from_secs(read().unix_timestamp());
+    time::set_wall_clock(timestamp);
+}
+
+/// Enables the periodic interrupt at 32768 >> (`rate` - 1) Hz.
+///
+/// `rate` must be between 3 (8192 Hz) and 15 (2 Hz). Fails if another
+/// handler is registered for `IRQ`, enabling it again only changes the rate.
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), IrqError> {
+    assert!((3..=15).contains(&rate), "invalid RTC rate {}", rate);
+
+    if !IRQ_REGISTERED.load(Ordering::Relaxed) {
+        irq::register_irq(IRQ, IrqHandler::Fn(handle_interrupt))?;
+        IRQ_REGISTERED.store(true, Ordering::Relaxed);
+    }
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        // the interrupt is only raised again once register C was read
+        cmos.read(STATUS_C);
+    });
+    PERIODIC_RATE.store(rate, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stops the periodic interrupt.
+///
+/// The handler of `IRQ` is only unregistered if it was registered by
+/// `enable_periodic_interrupt`.
+pub fn disable_periodic_interrupt() {
+    if IRQ_REGISTERED.swap(false, Ordering::Relaxed) {
+        irq::unregister_irq(IRQ);
+    }
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+    });
+    PERIODIC_RATE.store(0, Ordering::Relaxed);
+}
+
+/// Frequency of the periodic interrupt in Hz, zero if it is disabled
+pub fn periodic_frequency() -> u64 {
+    match PERIODIC_RATE.load(Ordering::Relaxed) {
+        0 => 0,
+        rate => 32768 >> (rate - 1),
+    }
+}
+
+/// Number of periodic interrupts so far
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn handle_interrupt() {
+    // reading register C acknowledges the interrupt, it tells why it fired
+    let status_c = CMOS.lock().read(STATUS_C);
+    if status_c & STATUS_C_PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -9,6 +9,7 @@ use core::{
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timer;
 
 pub struct Task {
     id: TaskId,
@@ -0,0 +1,222 @@
+use crate::time::Instant;
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicU64};
+use core::time::Duration;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+lazy_static! {
+    /// Pending deadlines, the earliest one on top
+    static ref TIMERS: Mutex<BinaryHeap<Entry>> = Mutex::new(BinaryHeap::new());
+}
+
+struct Entry {
+    deadline: Instant,
+    /// Id of the `Sleep` that registered the entry
+    id: u64,
+    waker: Waker,
+}
+
+// ordered by deadline, reversed so that the max-heap yields the earliest one
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deadline, other.id).cmp(&(self.deadline, self.id))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+/// Called by the timer interrupt handler, wakes the tasks whose deadline passed.
+///
+/// Must not block or allocate. If a task is registering a timer right now
+/// the expired ones are picked up by the next tick. Entries are removed
+/// when their `Sleep` is dropped, so the executor still holds a reference
+/// to every waker woken here and dropping it does not free memory.
+pub(crate) fn advance() {
+    let mut timers = match TIMERS.try_lock() {
+        Some(timers) => timers,
+        None => return,
+    };
+    let now = Instant::now();
+    while timers.peek().map_or(false, |entry| entry.deadline <= now) {
+        if let Some(entry) = timers.pop() {
+            entry.waker.wake();
+        }
+    }
+}
+
+/// Number of deadlines waiting for the timer interrupt
+pub fn pending() -> usize {
+    without_interrupts(|| TIMERS.lock().len())
+}
+
+/// Future that completes once its deadline has passed
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    fn new(deadline: Instant) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Sleep {
+            deadline,
+            id: NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed),
+            registered: false,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Changes the deadline, also of a sleep that completed already
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if self.registered {
+            let id = self.id;
+            without_interrupts(|| TIMERS.lock().retain(|entry| entry.id != id));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+
+        // replace the entry of an earlier poll, the waker might have changed
+        let entry = Entry {
+            deadline: self.deadline,
+            id: self.id,
+            waker: cx.waker().clone(),
+        };
+        let registered = self.registered;
+        without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            if registered {
+                timers.retain(|entry| entry.id != self.id);
+            }
+            timers.push(entry);
+        });
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Waits until `duration` has passed
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::new(Instant::now() + duration)
+}
+
+/// Waits until `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep::new(deadline)
+}
+
+/// Error of a `Timeout` whose future did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Future that runs `future` for at most a given duration
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // `future` is never moved out of the pinned `Timeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Runs `future`, giving up with `Elapsed` if it did not complete within `duration`
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Stream that yields once every period
+#[derive(Debug)]
+pub struct Interval {
+    sleep: Sleep,
+    period: Duration,
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let deadline = self.sleep.deadline();
+        // keep the schedule, but skip periods that were missed completely
+        let mut next = deadline + self.period;
+        let now = Instant::now();
+        if next <= now {
+            next = now + self.period;
+        }
+        self.sleep.reset(next);
+        Poll::Ready(Some(deadline))
+    }
+}
+
+/// Yields right away and then every `period`, the items are the scheduled times.
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must not be zero");
+    Interval {
+        sleep: sleep_until(Instant::now()),
+        period,
+    }
+}
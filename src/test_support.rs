@@ -0,0 +1,57 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+const MESSAGE_CAPACITY: usize = 2048;
+
+/// The message of a panic, for tests that expect a specific panic.
+///
+/// Longer messages are cut off.
+pub struct PanicMessage {
+    buffer: [u8; MESSAGE_CAPACITY],
+    len: usize,
+}
+
+impl PanicMessage {
+    pub fn new(info: &PanicInfo) -> Self {
+        let mut message = PanicMessage {
+            buffer: [0; MESSAGE_CAPACITY],
+            len: 0,
+        };
+        let _ = write!(message, "{}", info);
+        message
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.buffer[..self.len];
+        match core::str::from_utf8(bytes) {
+            Ok(message) => message,
+            // the message was cut in the middle of a character
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+        }
+    }
+
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.as_str().contains(pattern)
+    }
+}
+
+impl Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        let len = bytes.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Returns the address of the code calling this.
+///
+/// Calls between two of these lie between the returned addresses.
+#[inline(always)]
+pub fn code_address() -> usize {
+    let rip: usize;
+    unsafe { asm!("lea {}, [rip]", out(reg) rip) };
+    rip
+}
@@ -0,0 +1,168 @@
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the programmable interval timer
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// Number of timer interrupts per second
+pub const TICK_HZ: u64 = 1000;
+
+const PIT_DIVISOR: u64 = PIT_FREQUENCY / TICK_HZ;
+/// Length of a tick, slightly longer than 1 ms as the divisor is rounded down
+pub const TICK_NANOS: u64 = PIT_DIVISOR * 1_000_000_000 / PIT_FREQUENCY;
+
+// PIT ports
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Controls the gate of channel 2 and reports its output
+const PIT_CHANNEL_2_GATE: u16 = 0x61;
+
+/// Length of the TSC calibration in PIT cycles, about 10 ms
+const CALIBRATION_CYCLES: u64 = PIT_FREQUENCY / 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value at the end of `init`, the zero point of `uptime`
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+/// Unix time in nanoseconds at which the uptime was zero
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT to raise the timer interrupt `TICK_HZ` times a second
+/// and measures the TSC frequency against it.
+///
+/// Takes about 10 ms and does not need interrupts to be enabled.
+pub fn init() {
+    unsafe {
+        // channel 0, low and high byte, mode 2 (rate generator)
+        Port::<u8>::new(PIT_COMMAND).write(0b0011_0100);
+        let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0);
+        channel_0.write(PIT_DIVISOR as u8);
+        channel_0.write((PIT_DIVISOR >> 8) as u8);
+    }
+
+    let cycles = measure_tsc();
+    TSC_FREQUENCY.store(cycles * PIT_FREQUENCY / CALIBRATION_CYCLES, Ordering::Relaxed);
+    TSC_START.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+}
+
+/// Returns the number of TSC cycles that pass in `CALIBRATION_CYCLES` PIT cycles.
+///
+/// Uses channel 2 in one-shot mode with the speaker disconnected, polling
+/// its output, so that channel 0 and the interrupts are left alone.
+fn measure_tsc() -> u64 {
+    let mut gate = Port::<u8>::new(PIT_CHANNEL_2_GATE);
+    unsafe {
+        // enable the gate, disable the speaker
+        let value = gate.read();
+        gate.write((value & !0b10) | 0b1);
+
+        // channel 2, low and high byte, mode 0 (interrupt on terminal count)
+        Port::<u8>::new(PIT_COMMAND).write(0b1011_0000);
+        let mut channel_2 = Port::<u8>::new(PIT_CHANNEL_2);
+        channel_2.write(CALIBRATION_CYCLES as u8);
+        channel_2.write((CALIBRATION_CYCLES >> 8) as u8);
+
+        let start = _rdtsc();
+        // bit 5 goes high once the count reached zero
+        while gate.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+        _rdtsc() - start
+    }
+}
+
+/// Called by the timer interrupt handler
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer interrupts since interrupts were enabled
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// TSC cycles per second, zero before `init`
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since `init`.
+///
+/// Read from the TSC, so consecutive calls never go backwards. Falls back
+/// to counting ticks if the TSC could not be calibrated.
+pub fn uptime_nanos() -> u64 {
+    let frequency = tsc_frequency();
+    if frequency == 0 {
+        return ticks() * TICK_NANOS;
+    }
+    let cycles = unsafe { _rdtsc() }.saturating_sub(TSC_START.load(Ordering::Relaxed));
+    (u128::from(cycles) * 1_000_000_000 / u128::from(frequency)) as u64
+}
+
+/// Time since `init`
+pub fn uptime() -> Duration {
+    Duration::from_nanos(uptime_nanos())
+}
+
+/// Sets the wall clock, `now` returns `timestamp` at this moment
+pub fn set_wall_clock(timestamp: Duration) {
+    let nanos = u64::try_from(timestamp.as_nanos()).unwrap_or(u64::MAX);
+    BOOT_TIME.store(nanos.saturating_sub(uptime_nanos()), Ordering::Relaxed);
+}
+
+/// Time since the Unix epoch.
+///
+/// Counts from zero at boot until the wall clock was set by `rtc::init`.
+pub fn now() -> Duration {
+    Duration::from_nanos(BOOT_TIME.load(Ordering::Relaxed) + uptime_nanos())
+}
+
+/// A point in time measured by the monotonic uptime clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(uptime_nanos())
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    /// Nanoseconds since `init`
+    pub fn as_nanos(self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        Instant(self.0.saturating_add(nanos))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
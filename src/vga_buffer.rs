@@ -137,7 +137,9 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        buffer: unsafe {
+            &mut *((crate::memory::PHYSICAL_MEMORY_START + 0xb8000) as *mut Buffer)
+        },
     });
 }
 
@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    address_space::{self, AddressSpace, AddressSpaceError},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// An address in the lower half, owned by the address spaces
+const USER_ADDR: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|kernel_memory| kernel_memory.frame_allocator.used_frames())
+}
+
+#[test_case]
+fn mappings_are_private() {
+    let addr = VirtAddr::new(USER_ADDR);
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    first.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+    second.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+
+    unsafe {
+        first.switch();
+        addr.as_mut_ptr::<u64>().write_volatile(1);
+        second.switch();
+        addr.as_mut_ptr::<u64>().write_volatile(2);
+        first.switch();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        address_space::switch_to_kernel();
+    }
+
+    let kernel_translation = memory::with_kernel_memory(|kernel_memory| {
+        memory::translate(&kernel_memory.mapper, addr)
+    });
+    assert!(kernel_translation.is_none());
+}
+
+#[test_case]
+fn kernel_mappings_are_shared() {
+    let space = AddressSpace::new().unwrap();
+    let kernel_code = VirtAddr::new(mappings_are_private as usize as u64);
+
+    let translation = space.translate(kernel_code).unwrap();
+    let kernel_translation = memory::with_kernel_memory(|kernel_memory| {
+        memory::translate(&kernel_memory.mapper, kernel_code)
+    })
+    .unwrap();
+    assert_eq!(translation.phys_addr, kernel_translation.phys_addr);
+}
+
+#[test_case]
+fn protect_changes_flags() {
+    let addr = VirtAddr::new(USER_ADDR);
+    let mut space = AddressSpace::new().unwrap();
+    space.map(addr, 2 * 4096, PageTableFlags::WRITABLE).unwrap();
+    space.protect(addr, 4096, PageTableFlags::empty()).unwrap();
+
+    let flags = space.translate(addr).unwrap().flags;
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(address_space::OWNED));
+    let flags = space.translate(addr + 4096u64).unwrap().flags;
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+
+    space.unmap(addr, 2 * 4096).unwrap();
+    assert!(space.translate(addr).is_none());
+    assert_eq!(space.unmap(addr, 4096), Err(AddressSpaceError::NotMapped));
+}
+
+#[test_case]
+fn kernel_half_is_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let kernel_addr = VirtAddr::new(0x_ffff_c000_0000_0000);
+    assert_eq!(
+        space.map(kernel_addr, 4096, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::KernelAddress)
+    );
+}
+
+#[test_case]
+fn range_reaching_past_the_lower_half_is_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let last_user_page = VirtAddr::new(0x_7fff_ffff_f000);
+    assert!(space
+        .map(last_user_page, 4096, PageTableFlags::WRITABLE)
+        .is_ok());
+    assert_eq!(
+        space.map(last_user_page, 2 * 4096, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::InvalidRange)
+    );
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    // the first address space may pin the kernel regions, which are never freed
+    drop(AddressSpace::new().unwrap());
+    let before = used_frames();
+
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(VirtAddr::new(USER_ADDR), 16 * 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert!(used_frames() > before + 16);
+    drop(space);
+
+    assert_eq!(used_frames(), before);
+}
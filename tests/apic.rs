@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::{self, apic, Controller};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    assert!(interrupts::enable_apic());
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn apic_is_active() {
+    assert_eq!(interrupts::controller(), Controller::Apic);
+    assert!(apic::enabled());
+}
+
+#[test_case]
+fn madt_lists_processor_and_io_apic() {
+    let madt = apic::madt().unwrap();
+    assert!(madt.processors >= 1);
+    assert!(madt.io_apics.iter().flatten().count() >= 1);
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    // hlt only returns once an interrupt was delivered, so this hangs if the
+    // timer is not routed through the I/O APIC or its EOI is lost
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn mask_and_unmask_irq() {
+    assert!(apic::set_irq_masked(3, false));
+    assert!(apic::set_irq_masked(3, true));
+    assert!(!apic::set_irq_masked(2, false));
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::allocator::bump::Arena;
+use core::panic::PanicInfo;
+
+const ARENA_SIZE: usize = 4096;
+
+/// Backing memory for the arenas under test
+static mut MEMORY: [u8; ARENA_SIZE] = [0; ARENA_SIZE];
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn new_arena() -> Arena {
+    unsafe { Arena::new(MEMORY.as_mut_ptr() as usize, ARENA_SIZE) }
+}
+
+#[test_case]
+fn vec_in_arena() {
+    let arena = new_arena();
+    let mut vec = Vec::new_in(&arena);
+    for i in 0..100u32 {
+        vec.push(i);
+    }
+
+    assert_eq!(vec.iter().sum::<u32>(), 99 * 100 / 2);
+    // the vec was the only allocation, so it grew in place
+    assert_eq!(arena.used(), vec.capacity() * 4);
+}
+
+#[test_case]
+fn reset_frees_everything() {
+    let mut arena = new_arena();
+    {
+        let mut vec = Vec::new_in(&arena);
+        vec.extend_from_slice(&[1u8; 128]);
+        // dropping would give the most recent allocation back to the arena
+        core::mem::forget(vec);
+    }
+    assert!(arena.used() > 0);
+
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+    assert_eq!(arena.remaining(), ARENA_SIZE);
+}
+
+#[test_case]
+fn nested_checkpoints() {
+    let mut arena = new_arena();
+    let outer = arena.checkpoint();
+    {
+        let mut vec = Vec::new_in(&arena);
+        vec.extend_from_slice(&[1u64; 4]);
+        core::mem::forget(vec);
+    }
+    let inner = arena.checkpoint();
+    {
+        let mut vec = Vec::new_in(&arena);
+        vec.extend_from_slice(&[2u64; 4]);
+        core::mem::forget(vec);
+    }
+    let used = arena.used();
+
+    arena.rollback(inner);
+    assert!(arena.used() > 0);
+    assert!(arena.used() < used);
+    arena.rollback(outer);
+    assert_eq!(arena.used(), 0);
+}
+
+#[test_case]
+fn dropping_last_allocation_frees_it() {
+    let arena = new_arena();
+    {
+        let mut vec = Vec::new_in(&arena);
+        vec.push(1u32);
+    }
+    assert_eq!(arena.used(), 0);
+}
+
+#[test_case]
+fn out_of_memory() {
+    let arena = new_arena();
+    let mut vec: Vec<u8, _> = Vec::new_in(&arena);
+    assert!(vec.try_reserve(ARENA_SIZE + 1).is_err());
+}
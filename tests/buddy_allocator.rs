@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    buddy::{self, BuddyFrameAllocator},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Runs `f` with the frame allocator of the kernel
+fn with_allocator<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    memory::with_kernel_memory(|kernel_memory| f(&mut kernel_memory.frame_allocator))
+}
+
+#[test_case]
+fn order_for_frame_counts() {
+    assert_eq!(buddy::order_for(1), Some(0));
+    assert_eq!(buddy::order_for(3), Some(2));
+    assert_eq!(buddy::order_for(512), Some(buddy::ORDER_2MIB));
+    assert_eq!(buddy::order_for(1 << (buddy::MAX_ORDER + 1)), None);
+}
+
+#[test_case]
+fn contiguous_block_is_aligned() {
+    with_allocator(|allocator| {
+        let block = allocator.allocate(4).unwrap();
+        assert!(block.is_aligned(16 * Size4KiB::SIZE));
+        unsafe { allocator.deallocate(block, 4) };
+    });
+}
+
+#[test_case]
+fn free_restores_counts() {
+    with_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let first: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+        let second: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_frames(), free - 2);
+
+        unsafe {
+            allocator.deallocate_frame(first);
+            allocator.deallocate_frame(second);
+        }
+        assert_eq!(allocator.free_frames(), free);
+    });
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    with_allocator(|allocator| {
+        // QEMU's default machine has enough memory for at least one 2 MiB block
+        let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+        unsafe { allocator.deallocate_frame(frame) };
+    });
+}
+
+#[test_case]
+fn kernel_memory_uses_the_buddy_allocator() {
+    // frames handed out for the heap come from the same allocator as huge frames
+    let used = with_allocator(|allocator| allocator.used_frames());
+    assert!(used > 0);
+}
@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use blog_os::memory;
+use blog_os::test_support::PanicMessage;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("buddy_double_free::double_free_panics...\t");
+
+    blog_os::test_init(boot_info);
+    memory::with_kernel_memory(|kernel_memory| {
+        let allocator = &mut kernel_memory.frame_allocator;
+        let block = allocator.allocate(1).unwrap();
+        unsafe {
+            allocator.deallocate(block, 1);
+            // the block is merged with its buddy by now, which must not hide the second free
+            allocator.deallocate(block, 1);
+        }
+    });
+
+    serial_println!("[double free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if PanicMessage::new(info).contains("double free of block") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    address_space::{self, AddressSpace, AddressSpaceError},
+    cow,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+/// An address in the lower half, owned by the address spaces
+const USER_ADDR: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn frame(space: &AddressSpace, addr: VirtAddr) -> PhysFrame {
+    PhysFrame::containing_address(space.translate(addr).unwrap().phys_addr)
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|kernel_memory| kernel_memory.frame_allocator.used_frames())
+}
+
+#[test_case]
+fn clone_shares_frames_read_only() {
+    let addr = VirtAddr::new(USER_ADDR);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+    let child = parent.clone_cow().unwrap();
+
+    assert_eq!(frame(&parent, addr), frame(&child, addr));
+    assert_eq!(cow::ref_count(frame(&parent, addr)), 2);
+    for space in [&parent, &child] {
+        let flags = space.translate(addr).unwrap().flags;
+        assert!(flags.contains(cow::COW));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+    }
+}
+
+#[test_case]
+fn write_copies_page() {
+    let addr = VirtAddr::new(USER_ADDR);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+    unsafe {
+        parent.switch();
+        addr.as_mut_ptr::<u64>().write_volatile(1);
+        address_space::switch_to_kernel();
+    }
+    let child = parent.clone_cow().unwrap();
+
+    unsafe {
+        child.switch();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        addr.as_mut_ptr::<u64>().write_volatile(2);
+        parent.switch();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        address_space::switch_to_kernel();
+    }
+
+    assert_ne!(frame(&parent, addr), frame(&child, addr));
+    assert_eq!(cow::ref_count(frame(&parent, addr)), 1);
+    let flags = child.translate(addr).unwrap().flags;
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(cow::COW));
+}
+
+#[test_case]
+fn last_writer_keeps_frame() {
+    let addr = VirtAddr::new(USER_ADDR);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+    let shared = frame(&parent, addr);
+    drop(parent.clone_cow().unwrap());
+
+    // the child is gone, so writing must not copy
+    unsafe {
+        parent.switch();
+        addr.as_mut_ptr::<u64>().write_volatile(3);
+        address_space::switch_to_kernel();
+    }
+    assert_eq!(frame(&parent, addr), shared);
+}
+
+#[test_case]
+fn shared_frames_are_freed_once() {
+    // the first address space may pin the kernel regions, which are never freed
+    drop(AddressSpace::new().unwrap());
+    let before = used_frames();
+
+    let mut parent = AddressSpace::new().unwrap();
+    parent
+        .map(VirtAddr::new(USER_ADDR), 8 * 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    let child = parent.clone_cow().unwrap();
+    drop(parent);
+    drop(child);
+
+    assert_eq!(used_frames(), before);
+}
+
+#[test_case]
+fn too_many_shared_frames_are_rejected() {
+    // more frames than the reference count table can hold
+    const PAGES: u64 = 4096;
+
+    let before = used_frames();
+    let mut parent = AddressSpace::new().unwrap();
+    parent
+        .map(
+            VirtAddr::new(USER_ADDR),
+            PAGES * 4096,
+            PageTableFlags::WRITABLE,
+        )
+        .unwrap();
+    assert!(matches!(
+        parent.clone_cow(),
+        Err(AddressSpaceError::TooManySharedFrames)
+    ));
+
+    // the failed clone released its mappings again
+    let last = VirtAddr::new(USER_ADDR + (PAGES - 1) * 4096);
+    assert_eq!(cow::ref_count(frame(&parent, last)), 1);
+    drop(parent);
+    assert_eq!(used_frames(), before);
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, vma};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const REGION_START: u64 = 0x_ffff_c055_0000_0000;
+const REGION_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|kernel_memory| kernel_memory.frame_allocator.used_frames())
+}
+
+#[test_case]
+fn register_does_not_map() {
+    let before = used_frames();
+    let start = VirtAddr::new(REGION_START);
+    vma::register("test", start, REGION_SIZE, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(used_frames(), before);
+    assert!(vma::find(start + 4096u64).is_some());
+    vma::unregister(start).unwrap();
+}
+
+#[test_case]
+fn sparse_region_is_backed_on_access() {
+    let start = VirtAddr::new(REGION_START);
+    vma::register("test", start, REGION_SIZE, PageTableFlags::WRITABLE).unwrap();
+    let before = used_frames();
+
+    // touch one byte every 64 MiB
+    let step = 64 * 1024 * 1024;
+    for offset in (0..REGION_SIZE).step_by(step) {
+        let ptr = (start + offset).as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0, "lazily mapped memory must be zeroed");
+            ptr.write_volatile(offset);
+        }
+    }
+    for offset in (0..REGION_SIZE).step_by(step) {
+        let ptr = (start + offset).as_ptr::<u64>();
+        assert_eq!(unsafe { ptr.read_volatile() }, offset);
+    }
+
+    // 16 data pages plus a few page tables, far less than the whole region
+    let touched = (REGION_SIZE as usize) / step;
+    let used = used_frames() - before;
+    assert!(used >= touched && used < touched * 3);
+
+    vma::unregister(start).unwrap();
+    assert!(used_frames() < before + used);
+}
+
+#[test_case]
+fn overlapping_region_is_rejected() {
+    let start = VirtAddr::new(REGION_START);
+    vma::register("test", start, REGION_SIZE, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(
+        vma::register("overlap", start + 4096u64, 4096, PageTableFlags::WRITABLE).unwrap_err(),
+        vma::VmaError::Overlap
+    );
+    vma::unregister(start).unwrap();
+}
+
+#[test_case]
+fn unaligned_region_is_rejected() {
+    let start = VirtAddr::new(REGION_START + 1);
+    assert_eq!(
+        vma::register("unaligned", start, 4096, PageTableFlags::WRITABLE).unwrap_err(),
+        vma::VmaError::InvalidRange
+    );
+}
+
+#[test_case]
+fn lower_half_region_is_rejected() {
+    // owned by the address spaces, faults there cannot go to the kernel table
+    let start = VirtAddr::new(0x_1000_0000_0000);
+    assert_eq!(
+        vma::register("user", start, 4096, PageTableFlags::WRITABLE).unwrap_err(),
+        vma::VmaError::InvalidRange
+    );
+}
@@ -0,0 +1,331 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::exceptions::{self, DescriptorTable, SelectorErrorCode, TrapFrame};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+/// Marks that no exception was raised
+const NONE: u64 = u64::MAX;
+
+static VECTOR: AtomicU64 = AtomicU64::new(NONE);
+static ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+/// Length of the faulting instruction, zero for traps
+static SKIP: AtomicU64 = AtomicU64::new(0);
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    exceptions::set_hook(Some(record_and_skip));
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn record_and_skip(frame: &mut TrapFrame) -> bool {
+    VECTOR.store(frame.vector, Ordering::Relaxed);
+    ERROR_CODE.store(frame.error_code, Ordering::Relaxed);
+    if frame.vector == 8 {
+        // the state saved for a double fault is undefined, `double_fault`
+        // keeps its stack pointer in r12 and where to resume in r13
+        frame.stack_frame.stack_pointer = VirtAddr::new(frame.registers.r12);
+        frame.stack_frame.instruction_pointer = VirtAddr::new(frame.registers.r13);
+    } else {
+        frame.stack_frame.instruction_pointer += SKIP.load(Ordering::Relaxed);
+    }
+    true
+}
+
+/// Runs `trigger`, which raises an exception at an instruction of `length`
+/// bytes, and returns the vector and error code
+fn raise(length: u64, trigger: impl FnOnce()) -> (u64, u64) {
+    VECTOR.store(NONE, Ordering::Relaxed);
+    SKIP.store(length, Ordering::Relaxed);
+    trigger();
+    (VECTOR.load(Ordering::Relaxed), ERROR_CODE.load(Ordering::Relaxed))
+}
+
+#[test_case]
+fn divide_error() {
+    let (vector, _) = raise(3, || unsafe {
+        asm!("div rcx", in("rcx") 0u64, inout("rax") 1u64 => _, inout("rdx") 0u64 => _);
+    });
+    assert_eq!(vector, 0);
+}
+
+#[test_case]
+fn debug() {
+    // int1 is a trap, the saved instruction pointer is already past it
+    let (vector, _) = raise(0, || unsafe { asm!(".byte 0xf1") });
+    assert_eq!(vector, 1);
+}
+
+#[test_case]
+fn non_maskable_interrupt() {
+    // QEMU only raises real NMIs from its monitor, `int 2` goes through the same gate
+    let (vector, _) = raise(0, || unsafe { asm!("int 2") });
+    assert_eq!(vector, 2);
+}
+
+#[test_case]
+fn breakpoint() {
+    let (vector, _) = raise(0, x86_64::instructions::interrupts::int3);
+    assert_eq!(vector, 3);
+}
+
+#[test_case]
+fn invalid_opcode() {
+    let (vector, _) = raise(2, || unsafe { asm!("ud2") });
+    assert_eq!(vector, 6);
+}
+
+#[test_case]
+fn device_not_available() {
+    unsafe { Cr0::update(|flags| *flags |= Cr0Flags::TASK_SWITCHED) };
+    let (vector, _) = raise(2, || unsafe { asm!("fnop") });
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+    assert_eq!(vector, 7);
+}
+
+#[test_case]
+fn double_fault() {
+    // the breakpoint cannot be pushed onto the non-canonical stack, and
+    // neither can the fault this raises, which makes it a double fault
+    let (vector, error_code) = raise(0, || unsafe {
+        asm!(
+            "mov r12, rsp",
+            "lea r13, [rip + 2f]",
+            "mov rsp, rcx",
+            "int3",
+            "2:",
+            in("rcx") 0x8000_0000_0000_0000u64,
+            out("r12") _,
+            out("r13") _,
+        );
+    });
+    assert_eq!((vector, error_code), (8, 0));
+}
+
+/// Marks a TSS descriptor as busy, set by `ltr`
+const TSS_BUSY: u64 = 1 << 41;
+/// Limit bits of a segment descriptor
+const LIMIT_MASK: u64 = 0xffff | (0xf << 48);
+/// Offset of the first interrupt stack pointer in the TSS
+const IST_OFFSET: u64 = 0x24;
+
+/// Writes `descriptor` to the TSS descriptor of `selector` and loads it again
+unsafe fn reload_tss(selector: u16, descriptor: u64) {
+    let mut gdt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    asm!("sgdt [{}]", in(reg) &mut gdt as *mut DescriptorTablePointer);
+    let entry = (gdt.base + u64::from(selector & !0b111)).as_mut_ptr::<u64>();
+    // `ltr` refuses busy descriptors
+    entry.write_volatile(descriptor & !TSS_BUSY);
+    load_tss(SegmentSelector(selector));
+}
+
+#[test_case]
+fn invalid_tss() {
+    let selector: u16;
+    let mut gdt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        asm!("str {:x}", out(reg) selector);
+        asm!("sgdt [{}]", in(reg) &mut gdt as *mut DescriptorTablePointer);
+    }
+    let entry = (gdt.base + u64::from(selector & !0b111)).as_ptr::<u64>();
+    let original = unsafe { entry.read_volatile() };
+
+    // the double fault gate switches to an interrupt stack, which can't be
+    // read from a TSS that ends before the interrupt stack table
+    let truncated = (original & !LIMIT_MASK) | (IST_OFFSET - 1);
+    let (vector, error_code) = without_interrupts(|| unsafe {
+        reload_tss(selector, truncated);
+        let result = raise(2, || asm!("int 8"));
+        reload_tss(selector, original);
+        result
+    });
+    assert_eq!(vector, 10);
+    let error_code = SelectorErrorCode::from(error_code);
+    assert_eq!(error_code.table, DescriptorTable::Gdt);
+    assert_eq!(error_code.index, selector >> 3);
+}
+
+#[test_case]
+fn segment_not_present() {
+    // vector 15 is reserved, its gate is not present
+    let (vector, error_code) = raise(2, || unsafe { asm!("int 15") });
+    assert_eq!(vector, 11);
+    let selector = SelectorErrorCode::from(error_code);
+    assert_eq!(selector.table, DescriptorTable::Idt);
+    assert_eq!(selector.index, 15);
+    assert!(!selector.external);
+}
+
+#[test_case]
+fn stack_segment_fault() {
+    // a non-canonical address based on rsp is checked against the stack segment
+    let (vector, error_code) = raise(4, || unsafe {
+        asm!("mov rax, [rsp + rcx]", in("rcx") 0x8000_0000_0000_0000u64, out("rax") _);
+    });
+    assert_eq!((vector, error_code), (12, 0));
+}
+
+#[test_case]
+fn general_protection_fault_non_canonical() {
+    let (vector, error_code) = raise(3, || unsafe {
+        asm!("mov rax, [rcx]", in("rcx") 0x8000_0000_0000_0000u64, out("rax") _);
+    });
+    assert_eq!((vector, error_code), (13, 0));
+}
+
+#[test_case]
+fn general_protection_fault_selector() {
+    // GDT index 10 lies beyond the end of the GDT
+    let (vector, error_code) = raise(2, || unsafe {
+        asm!("mov ds, eax", in("eax") 10u32 << 3);
+    });
+    assert_eq!(vector, 13);
+    let selector = SelectorErrorCode::from(error_code);
+    assert_eq!(selector.table, DescriptorTable::Gdt);
+    assert_eq!(selector.index, 10);
+    assert!(!selector.external);
+}
+
+#[test_case]
+fn x87_floating_point() {
+    // unmasks division by zero, CR0.NE reports it as #MF instead of IRQ 13
+    let control_word: u16 = 0x037f & !(1 << 2);
+    unsafe { Cr0::update(|flags| *flags |= Cr0Flags::NUMERIC_ERROR) };
+    let (vector, _) = raise(1, || unsafe {
+        asm!(
+            "fninit",
+            "fldcw [{}]",
+            "fld1",
+            "fldz",
+            "fdivp",
+            // the pending exception is raised by the next waiting instruction
+            "fwait",
+            "fninit",
+            in(reg) &control_word as *const u16,
+        );
+    });
+    assert_eq!(vector, 16);
+}
+
+// Alignment checks (vector 17) are only raised at CPL 3, and the kernel has
+// no user mode yet. In the kernel RFLAGS.AC only lifts SMAP, see
+// `memory::user`, so there is no test for them.
+
+#[test_case]
+fn simd_floating_point() {
+    // unmasks division by zero, CR4.OSXMMEXCPT reports it as #XM instead of #UD
+    let unmasked: u32 = 0x1f80 & !(1 << 9);
+    let default: u32 = 0x1f80;
+    unsafe {
+        Cr0::update(|flags| flags.remove(Cr0Flags::EMULATE_COPROCESSOR));
+        Cr4::update(|flags| *flags |= Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+    }
+    // `divss xmm0, xmm1` is 4 bytes long
+    let (vector, _) = raise(4, || unsafe { divide_by_zero_sse(unmasked, default) });
+    assert_eq!(vector, 19);
+}
+
+/// The kernel is built without SSE, so the assembler only accepts SSE
+/// instructions in functions that enable it
+#[target_feature(enable = "sse")]
+unsafe fn divide_by_zero_sse(unmasked: u32, default: u32) {
+    asm!(
+        "ldmxcsr [{unmasked}]",
+        "mov eax, 1",
+        "cvtsi2ss xmm0, eax",
+        "xorps xmm1, xmm1",
+        "divss xmm0, xmm1",
+        // also clears the exception flag
+        "ldmxcsr [{default}]",
+        unmasked = in(reg) &unmasked as *const u32,
+        default = in(reg) &default as *const u32,
+        out("eax") _,
+        out("xmm0") _,
+        out("xmm1") _,
+    );
+}
+
+/// Returns the handler address of the IDT entry of `vector`
+fn idt_handler(vector: usize) -> u64 {
+    let mut idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe { asm!("sidt [{}]", in(reg) &mut idt as *mut DescriptorTablePointer) };
+    let entry = (idt.base + vector as u64 * 16).as_ptr::<u16>();
+    unsafe {
+        let low = u64::from(entry.read_volatile());
+        let middle = u64::from(entry.add(3).read_volatile());
+        let high = u64::from(entry.add(4).cast::<u32>().read_volatile());
+        low | middle << 16 | high << 32
+    }
+}
+
+/// Machine checks, virtualization, control protection and security
+/// exceptions can't be raised under QEMU, this checks at least that their
+/// entries lead to the right stubs.
+#[test_case]
+fn idt_entries_point_to_stubs() {
+    extern "C" {
+        fn blog_os_exception_10();
+        fn blog_os_exception_11();
+        fn blog_os_exception_18();
+        fn blog_os_exception_20();
+        fn blog_os_exception_21();
+        fn blog_os_exception_30();
+    }
+    let stubs: [(usize, unsafe extern "C" fn()); 6] = [
+        (10, blog_os_exception_10),
+        (11, blog_os_exception_11),
+        (18, blog_os_exception_18),
+        (20, blog_os_exception_20),
+        (21, blog_os_exception_21),
+        (30, blog_os_exception_30),
+    ];
+    for (vector, stub) in stubs {
+        assert_eq!(idt_handler(vector), stub as u64, "vector {}", vector);
+    }
+}
+
+#[test_case]
+fn selector_error_code_tables() {
+    assert_eq!(SelectorErrorCode::from(0b010).table, DescriptorTable::Idt);
+    assert_eq!(SelectorErrorCode::from(0b100).table, DescriptorTable::Ldt);
+    assert_eq!(SelectorErrorCode::from(0b110).table, DescriptorTable::Idt);
+    assert!(SelectorErrorCode::from(0b001).external);
+}
+
+#[test_case]
+fn exception_names() {
+    assert_eq!(exceptions::name(13), "GENERAL PROTECTION FAULT");
+    assert_eq!(exceptions::name(17), "ALIGNMENT CHECK");
+    assert_eq!(
+        exceptions::name(exceptions::CONTROL_PROTECTION),
+        "CONTROL PROTECTION"
+    );
+}
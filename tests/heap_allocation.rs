@@ -7,22 +7,15 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, vec::Vec};
-use blog_os::allocator::HEAP_SIZE;
+use blog_os::allocator::{self, HEAP_SIZE};
+use blog_os::test_support::code_address;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use blog_os::allocator;
-    use blog_os::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    blog_os::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    blog_os::test_init(boot_info);
 
     test_main();
     loop {}
@@ -71,3 +64,74 @@ fn many_boxes_long_lived() {
 
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    let n = HEAP_SIZE * 2;
+    let mut vec: Vec<u8> = Vec::with_capacity(n);
+    vec.resize(n, 1);
+
+    assert_eq!(vec.len(), n);
+    assert_eq!(vec.iter().map(|&b| b as usize).sum::<usize>(), n);
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = allocator::heap_stats();
+    let value = Box::new([0u8; 64]);
+    let during = allocator::heap_stats();
+    drop(value);
+    let after = allocator::heap_stats();
+
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 64);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.deallocations, before.deallocations + 1);
+}
+
+#[test_case]
+fn tracker_reports_live_allocations() {
+    use allocator::tracker;
+
+    tracker::clear();
+    tracker::start();
+    let start = code_address();
+    let leaked = Box::new([0u8; 64]);
+    let end = code_address();
+    let freed = Box::new([0u8; 32]);
+    drop(freed);
+    tracker::stop();
+
+    assert_eq!(tracker::live_allocations(), (1, 64));
+    let site = tracker::allocation_site(leaked.as_ptr()).expect("allocation was tracked");
+    assert!((start..end).contains(&site));
+    drop(leaked);
+    assert_eq!(tracker::live_allocations(), (0, 0));
+}
+
+#[test_case]
+fn tracker_survives_a_full_table() {
+    use allocator::tracker;
+
+    let untracked = Box::new(0u64);
+    let mut boxes = Vec::with_capacity(1100);
+    tracker::clear();
+    tracker::start();
+    for i in 0..1100u64 {
+        boxes.push(Box::new(i));
+    }
+    tracker::stop();
+
+    let (live, _) = tracker::live_allocations();
+    assert_eq!(live as u64 + tracker::dropped(), 1100);
+    assert!(tracker::dropped() > 0);
+    // looking up an address that is not in the full table must end
+    assert_eq!(
+        tracker::allocation_site(&*untracked as *const u64 as *const u8),
+        None
+    );
+    drop(untracked);
+    drop(boxes);
+    assert_eq!(tracker::live_allocations(), (0, 0));
+}
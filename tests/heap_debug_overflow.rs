@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::test_support::{code_address, PanicMessage};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    should_detect_overflow();
+    serial_println!("[overflow not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Code addresses right before and after the allocation of the buffer
+static ALLOCATION_START: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATION_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the first address of the allocation site in a corruption report
+fn reported_site(message: &str) -> Option<usize> {
+    let (_, site) = message.split_once("allocated at [")?;
+    let end = site.find(|c| c == ',' || c == ']')?;
+    let site = site[..end].strip_prefix("0x")?;
+    usize::from_str_radix(site, 16).ok()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = PanicMessage::new(info);
+    let allocation =
+        ALLOCATION_START.load(Ordering::Relaxed)..ALLOCATION_END.load(Ordering::Relaxed);
+
+    let site_in_test =
+        reported_site(message.as_str()).map_or(false, |site| allocation.contains(&site));
+    if message.contains("buffer overflow") && site_in_test {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+fn should_detect_overflow() {
+    serial_print!("heap_debug_overflow::should_detect_overflow...\t");
+
+    ALLOCATION_START.store(code_address(), Ordering::Relaxed);
+    let mut buffer = Box::new([0u8; 16]);
+    ALLOCATION_END.store(code_address(), Ordering::Relaxed);
+
+    unsafe {
+        // write one byte past the end of the allocation
+        let ptr = buffer.as_mut_ptr();
+        ptr.add(16).write_volatile(0);
+    }
+    drop(buffer);
+}
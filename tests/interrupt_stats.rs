@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::irq::{self, IrqHandler};
+use blog_os::interrupts::stats::{self, SpuriousSource};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn nothing() {}
+
+#[test_case]
+fn timer_interrupts_are_counted() {
+    let before = stats::vector_stats(irq::vector(irq::TIMER));
+    for _ in 0..5 {
+        x86_64::instructions::hlt();
+    }
+    let after = stats::vector_stats(irq::vector(irq::TIMER));
+    assert!(after.deliveries >= before.deliveries + 5);
+    assert!(after.cycles > before.cycles);
+}
+
+#[test_case]
+fn exceptions_are_counted() {
+    let before = stats::vector_stats(3).deliveries;
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(stats::vector_stats(3).deliveries, before + 1);
+}
+
+#[test_case]
+fn registered_irq_is_counted() {
+    irq::register_irq(5, IrqHandler::Fn(nothing)).unwrap();
+    let before = stats::vector_stats(irq::vector(5)).deliveries;
+    unsafe { asm!("int 0x25") };
+    assert_eq!(stats::vector_stats(irq::vector(5)).deliveries, before + 1);
+    irq::unregister_irq(5);
+}
+
+#[test_case]
+fn spurious_irq_is_not_a_delivery() {
+    let before = stats::spurious(SpuriousSource::FirstPic);
+    // nothing is in service at the PIC, so this is taken as spurious
+    unsafe { asm!("int 0x27") };
+    assert_eq!(stats::spurious(SpuriousSource::FirstPic), before + 1);
+    assert_eq!(stats::vector_stats(irq::vector(7)).deliveries, 0);
+}
+
+#[test_case]
+fn reset_clears_counters() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        stats::reset();
+        assert_eq!(stats::vector_stats(3).deliveries, 0);
+        assert_eq!(stats::vector_stats(irq::vector(irq::TIMER)).deliveries, 0);
+        assert_eq!(stats::spurious(SpuriousSource::FirstPic), 0);
+    });
+}
+
+/// Keeps the first bytes written to it
+struct Buffer {
+    bytes: [u8; 1024],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn report_lists_delivered_vectors() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        stats::reset();
+        x86_64::instructions::interrupts::int3();
+
+        let mut buffer = Buffer {
+            bytes: [0; 1024],
+            len: 0,
+        };
+        write!(buffer, "{}", stats::report()).unwrap();
+        let report = core::str::from_utf8(&buffer.bytes[..buffer.len]).unwrap();
+        let mut lines = report.lines();
+        assert!(lines.next().unwrap().starts_with("VEC"));
+        let breakpoint = lines.next().unwrap();
+        assert!(breakpoint.trim_start().starts_with("3 "));
+        assert!(breakpoint.contains("BREAKPOINT"));
+        assert!(lines.next().unwrap().starts_with("SPU"));
+    });
+}
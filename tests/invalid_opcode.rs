@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+use blog_os::test_support::PanicMessage;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::invalid_opcode_dumps_registers...\t");
+
+    blog_os::init();
+    unsafe { asm!("ud2", in("rax") 0x1234_5678u64) };
+
+    serial_println!("[invalid opcode not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = PanicMessage::new(info);
+
+    if message.contains("EXCEPTION: INVALID OPCODE") && message.contains("rax=0x0000000012345678") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use blog_os::interrupts::irq::{self, IrqError, IrqHandler};
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Not wired to any device in QEMU
+const UNUSED_IRQ: u8 = 5;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Raises the vector of IRQ 5 in software
+fn raise_irq_5() {
+    unsafe { asm!("int 0x25") };
+}
+
+fn first_pic_mask() -> u8 {
+    unsafe { Port::<u8>::new(0x21).read() }
+}
+
+static FN_CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn count_call() {
+    FN_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn builtin_handlers_are_registered() {
+    assert!(irq::is_registered(irq::TIMER));
+    assert!(irq::is_registered(irq::KEYBOARD));
+    assert_eq!(irq::vector(irq::KEYBOARD), 33);
+}
+
+#[test_case]
+fn fn_handler_is_called() {
+    irq::register_irq(UNUSED_IRQ, IrqHandler::Fn(count_call)).unwrap();
+    let before = FN_CALLS.load(Ordering::Relaxed);
+    raise_irq_5();
+    assert_eq!(FN_CALLS.load(Ordering::Relaxed), before + 1);
+    assert!(irq::unregister_irq(UNUSED_IRQ).is_some());
+}
+
+#[test_case]
+fn closure_handler_is_called_until_unregistered() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let counter = calls.clone();
+    irq::register_irq(
+        UNUSED_IRQ,
+        IrqHandler::closure(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }),
+    )
+    .unwrap();
+
+    raise_irq_5();
+    raise_irq_5();
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+    assert!(irq::unregister_irq(UNUSED_IRQ).is_some());
+    raise_irq_5();
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+    assert!(!irq::is_registered(UNUSED_IRQ));
+}
+
+#[test_case]
+fn registration_errors() {
+    assert_eq!(
+        irq::register_irq(irq::TIMER, IrqHandler::Fn(count_call)).err(),
+        Some(IrqError::AlreadyRegistered)
+    );
+    assert_eq!(
+        irq::register_irq(2, IrqHandler::Fn(count_call)).err(),
+        Some(IrqError::InvalidIrq)
+    );
+    assert_eq!(
+        irq::register_irq(16, IrqHandler::Fn(count_call)).err(),
+        Some(IrqError::InvalidIrq)
+    );
+    assert!(irq::unregister_irq(UNUSED_IRQ).is_none());
+}
+
+#[test_case]
+fn mask_and_unmask_line() {
+    irq::register_irq(UNUSED_IRQ, IrqHandler::Fn(count_call)).unwrap();
+    assert_eq!(first_pic_mask() & (1 << UNUSED_IRQ), 0);
+
+    irq::mask_irq(UNUSED_IRQ);
+    assert_ne!(first_pic_mask() & (1 << UNUSED_IRQ), 0);
+    irq::unmask_irq(UNUSED_IRQ);
+    assert_eq!(first_pic_mask() & (1 << UNUSED_IRQ), 0);
+
+    irq::unregister_irq(UNUSED_IRQ);
+    assert_ne!(first_pic_mask() & (1 << UNUSED_IRQ), 0);
+}
+
+#[test_case]
+fn spurious_irq_7_is_ignored() {
+    irq::register_irq(7, IrqHandler::Fn(count_call)).unwrap();
+    let before = FN_CALLS.load(Ordering::Relaxed);
+    // the PIC has nothing in service, so this looks like a spurious IRQ 7
+    unsafe { asm!("int 0x27") };
+    assert_eq!(FN_CALLS.load(Ordering::Relaxed), before);
+    irq::unregister_irq(7);
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, stack};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|kernel_memory| kernel_memory.frame_allocator.used_frames())
+}
+
+#[test_case]
+fn stacks_do_not_overlap() {
+    let first = stack::allocate(64 * 1024).unwrap();
+    let second = stack::allocate(64 * 1024).unwrap();
+
+    assert_ne!(first.id(), second.id());
+    assert!(first.top() <= second.bottom() || second.top() <= first.bottom());
+    assert_eq!(first.size(), 64 * 1024);
+
+    unsafe {
+        stack::free(first);
+        stack::free(second);
+    }
+}
+
+#[test_case]
+fn whole_stack_is_mapped_up_front() {
+    let before = used_frames();
+    let stack = stack::allocate(256 * 1024).unwrap();
+    let after_allocate = used_frames();
+    assert!(after_allocate - before >= 64);
+
+    // the lowest usable address must not need a page fault, which could not
+    // take the kernel memory lock if the code on the stack holds it
+    let bottom = stack.bottom().as_mut_ptr::<u64>();
+    memory::with_kernel_memory(|_| unsafe {
+        bottom.write_volatile(42);
+        assert_eq!(bottom.read_volatile(), 42);
+    });
+    assert_eq!(used_frames(), after_allocate);
+
+    unsafe { stack::free(stack) };
+}
+
+#[test_case]
+fn guard_area_is_recognised() {
+    let stack = stack::allocate(16 * 1024).unwrap();
+
+    assert_eq!(stack::overflowed_stack(stack.bottom() - 1u64), Some(stack.id()));
+    assert_eq!(stack::overflowed_stack(stack.bottom()), None);
+    assert_eq!(stack::overflowed_stack(stack.top() - 8u64), None);
+
+    let id = stack.id();
+    let guard = stack.bottom() - 1u64;
+    unsafe { stack::free(stack) };
+    assert_eq!(stack::overflowed_stack(guard), None, "stack {} was freed", id);
+}
+
+#[test_case]
+fn invalid_sizes_are_rejected() {
+    assert_eq!(stack::allocate(0).unwrap_err(), stack::StackError::InvalidSize);
+    assert_eq!(
+        stack::allocate(stack::MAX_STACK_SIZE + 1).unwrap_err(),
+        stack::StackError::InvalidSize
+    );
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::linked_list::{FitStrategy, LinkedListAllocator};
+use blog_os::allocator::stats::{AllocatorDetails, AllocatorStats};
+use blog_os::allocator::Locked;
+use core::alloc::{GlobalAlloc, Layout};
+use core::panic::PanicInfo;
+
+const HEAP_SIZE: usize = 8 * 1024;
+
+/// Memory for the allocators under test, independent of the kernel heap
+static mut HEAP: [u64; HEAP_SIZE / 8] = [0; HEAP_SIZE / 8];
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn new_allocator(strategy: FitStrategy) -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe { allocator.lock().init(HEAP.as_mut_ptr() as usize, HEAP_SIZE) };
+    allocator.lock().set_strategy(strategy);
+    allocator
+}
+
+fn free_regions(allocator: &Locked<LinkedListAllocator>) -> (usize, usize) {
+    match allocator.lock().details() {
+        AllocatorDetails::LinkedList {
+            free_regions,
+            free_bytes,
+        } => (free_regions, free_bytes),
+        _ => unreachable!(),
+    }
+}
+
+/// Allocates two blocks separated by small spacers and frees the blocks again,
+/// leaving free regions of 128 and 64 bytes in front of the rest of the heap.
+unsafe fn make_holes(allocator: &Locked<LinkedListAllocator>) -> (*mut u8, *mut u8) {
+    let large = Layout::from_size_align(128, 8).unwrap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let spacer = Layout::from_size_align(16, 8).unwrap();
+
+    let first = allocator.alloc(large);
+    allocator.alloc(spacer);
+    let second = allocator.alloc(small);
+    allocator.alloc(spacer);
+
+    allocator.dealloc(first, large);
+    allocator.dealloc(second, small);
+    (first, second)
+}
+
+#[test_case]
+fn adjacent_regions_are_merged() {
+    let allocator = new_allocator(FitStrategy::FirstFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe {
+        let a = allocator.alloc(layout);
+        let b = allocator.alloc(layout);
+        let c = allocator.alloc(layout);
+        allocator.dealloc(a, layout);
+        allocator.dealloc(c, layout);
+        allocator.dealloc(b, layout);
+    }
+
+    assert_eq!(free_regions(&allocator), (1, HEAP_SIZE));
+}
+
+#[test_case]
+fn first_fit_uses_first_hole() {
+    let allocator = new_allocator(FitStrategy::FirstFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe {
+        let (first, _) = make_holes(&allocator);
+        assert_eq!(allocator.alloc(layout), first);
+    }
+}
+
+#[test_case]
+fn best_fit_uses_smallest_hole() {
+    let allocator = new_allocator(FitStrategy::BestFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe {
+        let (_, second) = make_holes(&allocator);
+        assert_eq!(allocator.alloc(layout), second);
+    }
+}
+
+#[test_case]
+fn next_fit_continues_after_last_allocation() {
+    let allocator = new_allocator(FitStrategy::NextFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe {
+        let (first, second) = make_holes(&allocator);
+        let ptr = allocator.alloc(layout);
+        assert_ne!(ptr, first);
+        assert_ne!(ptr, second);
+    }
+}
@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, MappedPageSize};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    *MAPPER.lock() = Some(unsafe { memory::init(phys_mem_offset) });
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn translate_vga_buffer() {
+    let guard = MAPPER.lock();
+    let mapper = guard.as_ref().unwrap();
+
+    // the vga buffer is identity mapped by the bootloader
+    let translation = memory::translate(mapper, VirtAddr::new(0xb8000)).unwrap();
+    assert_eq!(translation.phys_addr, PhysAddr::new(0xb8000));
+    assert!(translation.flags.contains(PageTableFlags::PRESENT));
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    let guard = MAPPER.lock();
+    let mapper = guard.as_ref().unwrap();
+    let offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+
+    // the physical memory mapping commonly uses huge pages
+    let addr = VirtAddr::new(offset + 0x20_1234);
+    let translation = memory::translate(mapper, addr).unwrap();
+    assert_eq!(translation.phys_addr, PhysAddr::new(0x20_1234));
+    assert!(translation.page_size.bytes() >= MappedPageSize::Size4KiB.bytes());
+}
+
+#[test_case]
+fn translate_unmapped_address() {
+    let guard = MAPPER.lock();
+    let mapper = guard.as_ref().unwrap();
+
+    // the null page is never mapped
+    assert!(memory::translate(mapper, VirtAddr::new(0)).is_none());
+}
+
+#[test_case]
+fn walk_finds_vga_buffer() {
+    let mut guard = MAPPER.lock();
+    let mapper = guard.as_mut().unwrap();
+
+    let mut found = None;
+    memory::for_each_mapping(mapper.level_4_table(), |virt, phys, page_size, _| {
+        if virt.as_u64() <= 0xb8000 && 0xb8000 < virt.as_u64() + page_size.bytes() {
+            found = Some(phys + (0xb8000 - virt.as_u64()));
+        }
+    });
+    assert_eq!(found, Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn walk_visits_addresses_in_order() {
+    let mut guard = MAPPER.lock();
+    let mapper = guard.as_mut().unwrap();
+
+    let mut last = None;
+    let mut mappings = 0;
+    memory::for_each_mapping(mapper.level_4_table(), |virt, _, _, _| {
+        assert!(last.map_or(true, |last| last < virt));
+        last = Some(virt);
+        mappings += 1;
+    });
+    assert!(mappings > 0);
+}
+
+#[test_case]
+fn kernel_code_is_the_only_executable_memory() {
+    let mut guard = MAPPER.lock();
+    let mapper = guard.as_mut().unwrap();
+
+    // `memory::init` already enforced it, so a second audit changes nothing
+    let audit = memory::enforce_write_xor_execute(mapper);
+    assert!(audit.code_pages > 0);
+    assert_eq!(audit.code_made_executable, 0);
+    assert_eq!(audit.data_made_no_execute, 0);
+
+    let code = VirtAddr::new(kernel_code_is_the_only_executable_memory as usize as u64);
+    let flags = memory::translate(mapper, code).unwrap().flags;
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+
+    let phys = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let flags = memory::translate(mapper, phys).unwrap().flags;
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::irq::{self, IrqError, IrqHandler};
+use blog_os::rtc::{self, DateTime};
+use blog_os::time;
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+    DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+#[test_case]
+fn unix_timestamp_of_known_dates() {
+    assert_eq!(date(1970, 1, 1, 0, 0, 0).unix_timestamp(), 0);
+    assert_eq!(date(2000, 3, 1, 0, 0, 0).unix_timestamp(), 951_868_800);
+    assert_eq!(date(2024, 2, 29, 12, 0, 0).unix_timestamp(), 1_709_208_000);
+}
+
+#[test_case]
+fn timestamp_round_trip() {
+    for timestamp in [0, 951_782_399, 1_709_208_000, 4_102_444_799] {
+        let date = DateTime::from_unix_timestamp(timestamp);
+        assert_eq!(date.unix_timestamp(), timestamp);
+    }
+    assert_eq!(
+        DateTime::from_unix_timestamp(951_782_399),
+        date(2000, 2, 29, 23, 59, 59)
+    );
+}
+
+#[test_case]
+fn cmos_clock_is_valid() {
+    let now = rtc::read();
+    assert!(now.year >= 2020);
+    assert!((1..=12).contains(&now.month));
+    assert!((1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn wall_clock_follows_cmos_clock() {
+    let cmos = rtc::read().unix_timestamp();
+    let now = time::now().as_secs();
+    // the wall clock was set at most a second too early
+    assert!(now + 2 >= cmos && now <= cmos + 2);
+}
+
+#[test_case]
+fn periodic_interrupt_fires() {
+    // 1024 Hz
+    rtc::enable_periodic_interrupt(6).unwrap();
+    assert_eq!(rtc::periodic_frequency(), 1024);
+    let start = rtc::periodic_ticks();
+    let target = time::ticks() + 20;
+    while time::ticks() < target {
+        x86_64::instructions::hlt();
+    }
+    rtc::disable_periodic_interrupt();
+    assert!(rtc::periodic_ticks() > start);
+}
+
+#[test_case]
+fn enabling_twice_changes_the_rate() {
+    rtc::enable_periodic_interrupt(6).unwrap();
+    rtc::enable_periodic_interrupt(7).unwrap();
+    assert_eq!(rtc::periodic_frequency(), 512);
+    rtc::disable_periodic_interrupt();
+    assert!(!irq::is_registered(rtc::IRQ));
+}
+
+#[test_case]
+fn handler_of_another_driver_is_kept() {
+    fn other_handler() {}
+
+    irq::register_irq(rtc::IRQ, IrqHandler::Fn(other_handler)).unwrap();
+    assert_eq!(
+        rtc::enable_periodic_interrupt(6),
+        Err(IrqError::AlreadyRegistered)
+    );
+    assert_eq!(rtc::periodic_frequency(), 0);
+
+    rtc::disable_periodic_interrupt();
+    assert!(irq::is_registered(rtc::IRQ));
+    irq::unregister_irq(rtc::IRQ);
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::slab::{ObjectCache, TypedCache};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+static OBJECTS: ObjectCache = ObjectCache::new("test-objects", 100, 8);
+static POINTS: TypedCache<Point> = TypedCache::new("test-points");
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: u64,
+    y: u64,
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocates_distinct_objects() {
+    let a = OBJECTS.alloc().unwrap();
+    let b = OBJECTS.alloc().unwrap();
+    assert_ne!(a, b);
+    assert_eq!(OBJECTS.stats().objects_in_use, 2);
+
+    unsafe {
+        OBJECTS.free(a);
+        OBJECTS.free(b);
+    }
+    assert_eq!(OBJECTS.stats().objects_in_use, 0);
+}
+
+#[test_case]
+fn fills_multiple_slabs() {
+    let count = OBJECTS.objects_per_slab() * 3;
+    let mut objects = [None; 256];
+    assert!(count <= objects.len());
+
+    for slot in objects.iter_mut().take(count) {
+        *slot = OBJECTS.alloc();
+    }
+    assert_eq!(OBJECTS.stats().slabs, 3);
+
+    for object in objects.iter().take(count) {
+        unsafe { OBJECTS.free(object.unwrap()) };
+    }
+
+    // one empty slab is kept, the others went back to the frame allocator
+    let stats = OBJECTS.stats();
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.slabs, 1);
+
+    assert_eq!(OBJECTS.shrink(), 1);
+    assert_eq!(OBJECTS.stats().slabs, 0);
+}
+
+#[test_case]
+fn typed_cache() {
+    let point = POINTS.alloc(Point { x: 1, y: 2 }).unwrap();
+    assert_eq!(unsafe { point.as_ref() }, &Point { x: 1, y: 2 });
+    assert_eq!(POINTS.stats().object_size, 16);
+    unsafe { POINTS.free(point) };
+}
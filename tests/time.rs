@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::time;
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Waits until `count` more timer interrupts happened
+fn wait_ticks(count: u64) {
+    let target = time::ticks() + count;
+    while time::ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn tsc_is_calibrated() {
+    // every CPU QEMU emulates runs at well above 100 MHz
+    assert!(time::tsc_frequency() > 100_000_000);
+}
+
+#[test_case]
+fn ticks_advance() {
+    let before = time::ticks();
+    wait_ticks(5);
+    assert!(time::ticks() >= before + 5);
+}
+
+#[test_case]
+fn uptime_is_monotonic() {
+    let mut last = time::uptime_nanos();
+    for _ in 0..1000 {
+        let now = time::uptime_nanos();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn uptime_matches_ticks() {
+    let start = time::uptime_nanos();
+    wait_ticks(50);
+    let elapsed = time::uptime_nanos() - start;
+    // 49 to 50 full ticks passed, allow generous slack for the emulation
+    let expected = 50 * time::TICK_NANOS;
+    assert!(elapsed > expected / 2, "elapsed {} ns", elapsed);
+    assert!(elapsed < expected * 2, "elapsed {} ns", elapsed);
+}
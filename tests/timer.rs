@@ -0,0 +1,186 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use blog_os::task::timer::{self, Elapsed};
+use blog_os::task::{simple_executor::SimpleExecutor, Task};
+use blog_os::time::{self, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::cell::Cell;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+use futures_util::stream::{Stream, StreamExt};
+use x86_64::instructions::hlt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Runs `future` to completion
+fn run(future: impl Future<Output = ()> + 'static) {
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(future));
+    executor.run();
+}
+
+/// Waker that counts how often it was woken in `counter`
+fn counting_waker(counter: &'static AtomicUsize) -> Waker {
+    fn clone(data: *const ()) -> RawWaker {
+        RawWaker::new(data, &VTABLE)
+    }
+    fn wake(data: *const ()) {
+        let counter = unsafe { &*(data as *const AtomicUsize) };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+    let data = counter as *const AtomicUsize as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+}
+
+/// Halts until the timer interrupt has run a few times after `deadline`.
+///
+/// Checks that `wakes` stays zero as long as the deadline has not passed.
+fn wait_past(deadline: Instant, wakes: &AtomicUsize) {
+    loop {
+        // read before the time, a wake counted here happened before `now`
+        let woken = wakes.load(Ordering::Relaxed);
+        if Instant::now() >= deadline {
+            break;
+        }
+        assert_eq!(woken, 0, "woken before the deadline");
+        hlt();
+    }
+
+    // the first tick after the deadline wakes, the others must not wake again
+    let tick = time::ticks();
+    while time::ticks() < tick + 3 {
+        hlt();
+    }
+}
+
+#[test_case]
+fn sleep_wakes_once_after_deadline() {
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    let waker = counting_waker(&WAKES);
+    let mut context = Context::from_waker(&waker);
+
+    let mut sleep = timer::sleep(Duration::from_millis(20));
+    assert!(Pin::new(&mut sleep).poll(&mut context).is_pending());
+    wait_past(sleep.deadline(), &WAKES);
+
+    assert_eq!(WAKES.load(Ordering::Relaxed), 1);
+    assert!(Pin::new(&mut sleep).poll(&mut context).is_ready());
+}
+
+#[test_case]
+fn interval_wakes_once_per_period() {
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    let waker = counting_waker(&WAKES);
+    let mut context = Context::from_waker(&waker);
+
+    let period = Duration::from_millis(20);
+    let mut interval = timer::interval(period);
+    // the first item is ready right away
+    let first = match Pin::new(&mut interval).poll_next(&mut context) {
+        Poll::Ready(Some(first)) => first,
+        other => panic!("first tick not ready: {:?}", other),
+    };
+    assert!(Pin::new(&mut interval).poll_next(&mut context).is_pending());
+    wait_past(first + period, &WAKES);
+
+    assert_eq!(WAKES.load(Ordering::Relaxed), 1);
+    assert!(Pin::new(&mut interval).poll_next(&mut context).is_ready());
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    run(async {
+        let start = Instant::now();
+        timer::sleep(Duration::from_millis(20)).await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    });
+    assert_eq!(timer::pending(), 0);
+}
+
+#[test_case]
+fn sleep_until_past_deadline_is_ready() {
+    run(async {
+        let start = Instant::now();
+        timer::sleep_until(start).await;
+        assert!(start.elapsed() < Duration::from_millis(5));
+    });
+}
+
+#[test_case]
+fn sleeps_complete_in_deadline_order() {
+    let order = Rc::new(Cell::new(0));
+    let mut executor = SimpleExecutor::new();
+    for (i, millis) in [(1, 30), (0, 10)] {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            timer::sleep(Duration::from_millis(millis)).await;
+            assert_eq!(order.get(), i);
+            order.set(i + 1);
+        }));
+    }
+    executor.run();
+    assert_eq!(order.get(), 2);
+}
+
+#[test_case]
+fn timeout_elapses() {
+    run(async {
+        let slow = timer::sleep(Duration::from_millis(50));
+        let result = timer::timeout(Duration::from_millis(5), slow).await;
+        assert_eq!(result, Err(Elapsed));
+    });
+    // the dropped sleeps removed their deadlines
+    assert_eq!(timer::pending(), 0);
+}
+
+#[test_case]
+fn timeout_returns_output() {
+    run(async {
+        let fast = async {
+            timer::sleep(Duration::from_millis(5)).await;
+            42
+        };
+        let result = timer::timeout(Duration::from_millis(50), fast).await;
+        assert_eq!(result, Ok(42));
+    });
+}
+
+#[test_case]
+fn interval_yields_periodically() {
+    run(async {
+        let period = Duration::from_millis(5);
+        let mut interval = timer::interval(period);
+        let first = interval.next().await.unwrap();
+        for i in 1..4 {
+            let tick = interval.next().await.unwrap();
+            // periods are only skipped if one was missed completely
+            assert!(tick - first >= period * i);
+        }
+    });
+}
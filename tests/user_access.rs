@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    address_space::{self, AddressSpace},
+    user::{self, UserAccessError},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// An address in the lower half, owned by the address spaces
+const USER_ADDR: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Runs `f` with an address space that maps one user page at `USER_ADDR`
+fn with_user_page(flags: PageTableFlags, f: impl FnOnce()) {
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(
+            VirtAddr::new(USER_ADDR),
+            4096,
+            flags | PageTableFlags::USER_ACCESSIBLE,
+        )
+        .unwrap();
+
+    unsafe { space.switch() };
+    f();
+    unsafe { address_space::switch_to_kernel() };
+}
+
+#[test_case]
+fn copy_round_trip() {
+    with_user_page(PageTableFlags::WRITABLE, || {
+        let addr = VirtAddr::new(USER_ADDR + 100);
+        user::copy_to_user(addr, b"hello").unwrap();
+
+        let mut buffer = [0u8; 5];
+        user::copy_from_user(&mut buffer, addr).unwrap();
+        assert_eq!(&buffer, b"hello");
+    });
+}
+
+#[test_case]
+fn kernel_addresses_are_rejected() {
+    let mut buffer = [0u8; 8];
+    let kernel_addr = VirtAddr::new(&buffer as *const _ as u64);
+    assert_eq!(
+        user::copy_from_user(&mut buffer, kernel_addr),
+        Err(UserAccessError::NotUserAddress)
+    );
+}
+
+#[test_case]
+fn unmapped_pages_are_rejected() {
+    with_user_page(PageTableFlags::WRITABLE, || {
+        // the range continues past the only mapped page
+        let addr = VirtAddr::new(USER_ADDR + 4090);
+        assert_eq!(
+            user::copy_to_user(addr, &[0; 16]),
+            Err(UserAccessError::NotMapped)
+        );
+    });
+}
+
+#[test_case]
+fn read_only_pages_are_not_written() {
+    with_user_page(PageTableFlags::empty(), || {
+        let addr = VirtAddr::new(USER_ADDR);
+        assert_eq!(
+            user::copy_to_user(addr, &[1]),
+            Err(UserAccessError::NotWritable)
+        );
+        let mut buffer = [1u8];
+        user::copy_from_user(&mut buffer, addr).unwrap();
+        assert_eq!(buffer, [0]);
+    });
+}